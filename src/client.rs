@@ -0,0 +1,40 @@
+use std::time::Duration;
+use hyper::{ Client, Body };
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+
+/// The client used to proxy HTTP(S) requests. It is cheap to clone, and
+/// clones share the same pool of connections.
+pub type HttpClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+/// Options to configure how connections to destinations are pooled.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ClientOptions {
+    /// Should connections be kept open and reused for later requests?
+    pub keep_alive: bool,
+    /// How long can a connection sit idle in the pool before it's closed?
+    /// `None` means that idle connections are never closed.
+    pub idle_timeout: Option<Duration>,
+    /// How many idle connections can be kept open to each host?
+    pub max_idle_per_host: usize
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            keep_alive: true,
+            idle_timeout: Some(Duration::from_secs(90)),
+            max_idle_per_host: usize::MAX
+        }
+    }
+}
+
+/// Build a client to proxy requests with. This should be shared across
+/// requests so that connections (and TLS sessions) can be reused.
+pub fn new(opts: &ClientOptions) -> HttpClient {
+    Client::builder()
+        .keep_alive(opts.keep_alive)
+        .keep_alive_timeout(opts.idle_timeout)
+        .max_idle_per_host(opts.max_idle_per_host)
+        .build(HttpsConnector::new())
+}
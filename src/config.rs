@@ -0,0 +1,435 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+use serde::Deserialize;
+use hyper::StatusCode;
+use hyper::header::HeaderValue;
+use toml::Spanned;
+use crate::errors::{ Error };
+use crate::location::{ Protocol };
+use crate::routes::{ self, Route };
+use crate::tls::{ TlsFiles };
+use crate::health::{ HealthCheck };
+use crate::cors::{ Cors };
+
+/// The contents of a config file. Routes are declared as an array of
+/// `[[route]]` tables, each of which has a source and destination location
+/// given in exactly the same format as they would be on the command line.
+#[derive(Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    route: Vec<RouteConfig>
+}
+
+/// A single route in the config file, plus any options for it.
+#[derive(Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    src: Spanned<String>,
+    dest: Spanned<String>,
+    /// Equivalent to following the destination location with 'or' and each of these.
+    #[serde(default)]
+    fallback: Vec<String>,
+    /// Equivalent to prefixing the source location with '='.
+    #[serde(default)]
+    exact: bool,
+    /// Equivalent to prefixing the source location with eg 'GET,HEAD='.
+    #[serde(default)]
+    methods: Vec<String>,
+    /// The PEM encoded certificate chain to use for an HTTPS source.
+    tls_cert: Option<PathBuf>,
+    /// The PEM encoded private key to use for an HTTPS source.
+    tls_key: Option<PathBuf>,
+    /// The `Cache-Control` header to send back with files.
+    cache_control: Option<String>,
+    /// List the contents of directories that don't contain an index file.
+    #[serde(default)]
+    directory_listing: bool,
+    /// Status codes that cause the fallback destinations to be tried.
+    fallback_on: Option<Vec<u16>>,
+    /// How to spread requests across destinations that list several upstreams.
+    balance: Option<String>,
+    /// Consecutive failures to reach a destination before it's considered unhealthy.
+    max_fails: Option<usize>,
+    /// How often (in seconds) to probe destinations to check that they are healthy.
+    health_check_interval: Option<u64>,
+    /// The path to request when probing HTTP destinations.
+    health_check_path: Option<String>,
+    /// 'preserve', 'upstream' or the value to send as the `Host` header.
+    host_header: Option<String>,
+    /// Add to forwarding headers that requests arrive with, rather than replacing them.
+    #[serde(default)]
+    trust_forwarded_headers: bool,
+    /// Changes to make to the headers of requests that are proxied, eg 'set Name: value'.
+    #[serde(default)]
+    request_headers: Vec<String>,
+    /// Changes to make to the headers of responses.
+    #[serde(default)]
+    response_headers: Vec<String>,
+    /// Handle CORS for the route.
+    cors: Option<CorsConfig>
+}
+
+/// Which cross-origin requests a route allows.
+#[derive(Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CorsConfig {
+    /// Origins that can make requests (any, if not provided).
+    #[serde(default)]
+    origins: Vec<String>,
+    /// Methods that can be used (whichever is asked for, if not provided).
+    #[serde(default)]
+    methods: Vec<String>,
+    /// Headers that can be sent (whichever are asked for, if not provided).
+    #[serde(default)]
+    headers: Vec<String>,
+    #[serde(default)]
+    credentials: bool,
+    /// How long (in seconds) preflight responses can be cached for.
+    max_age: Option<u64>
+}
+
+/// Load routes from the config file at the path provided.
+pub fn from_file(path: impl AsRef<Path>) -> Result<Vec<Route>, Error> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path).map_err(|e| {
+        err!("could not read config file '{}': {}", path.display(), e)
+    })?;
+    from_str(&contents, &path.to_string_lossy())
+}
+
+/// Parse routes from the contents of a config file. The name is used to
+/// give context to any errors that are encountered.
+pub fn from_str(contents: &str, name: &str) -> Result<Vec<Route>, Error> {
+    let config: Config = toml::from_str(contents).map_err(|e| {
+        err!("{}: {}", name, e)
+    })?;
+
+    let mut routes = vec![];
+    for route in config.route {
+        let mut src_str = route.src.get_ref().to_owned();
+        if route.exact && !src_str.starts_with('=') {
+            src_str.insert(0, '=');
+        }
+        if !route.methods.is_empty() {
+            src_str = format!("{}={}", route.methods.join(","), src_str);
+        }
+
+        // Errors point at the first line of the route that they relate to:
+        let line = line_number(contents, route.src.start().min(route.dest.start()));
+        let parsed = routes::parse_route(&src_str, route.dest.get_ref()).and_then(|mut parsed| {
+            for fallback in &route.fallback {
+                parsed.add_fallback(fallback)?;
+            }
+            parsed.options.tls = match (route.tls_cert, route.tls_key) {
+                (Some(cert), Some(key)) => Some(TlsFiles { cert, key }),
+                (None, None) => None,
+                _ => return Err(err!("'tls_cert' and 'tls_key' must be provided together"))
+            };
+            if parsed.options.tls.is_some() && parsed.protocol() != Protocol::Https {
+                return Err(err!("'tls_cert' and 'tls_key' can only be provided for {} routes", Protocol::Https))
+            }
+            if let Some(cache_control) = route.cache_control {
+                if parsed.protocol() == Protocol::Tcp {
+                    return Err(err!("'cache_control' cannot be provided for {} routes", Protocol::Tcp))
+                }
+                let value = HeaderValue::from_str(&cache_control)
+                    .map_err(|_| err!("'{}' is not a valid 'cache_control' value", cache_control))?;
+                parsed.options.cache_control = Some(value);
+            }
+            parsed.options.directory_listing = route.directory_listing;
+            if let Some(codes) = route.fallback_on {
+                parsed.options.fallback_on = codes.into_iter().map(|code| {
+                    StatusCode::from_u16(code).map_err(|_| err!("'{}' is not a valid status code", code))
+                }).collect::<Result<_,_>>()?;
+            }
+            if let Some(balance) = route.balance {
+                parsed.options.balance = balance.parse()?;
+            }
+            if let Some(max_fails) = route.max_fails {
+                if max_fails == 0 {
+                    return Err(err!("'max_fails' must be at least 1"))
+                }
+                parsed.options.max_fails = max_fails;
+            }
+            parsed.options.health_check = match (route.health_check_interval, route.health_check_path) {
+                (Some(0), _) => return Err(err!("'health_check_interval' must be at least 1 second")),
+                (Some(secs), path) => {
+                    if path.is_some() && parsed.protocol() == Protocol::Tcp {
+                        return Err(err!("'health_check_path' cannot be provided for {} routes", Protocol::Tcp))
+                    }
+                    Some(HealthCheck {
+                        interval: Duration::from_secs(secs),
+                        path: routes::health_check_path(path.as_deref().unwrap_or("/"))
+                    })
+                },
+                (None, Some(_)) => return Err(err!("'health_check_path' requires 'health_check_interval' to be provided")),
+                (None, None) => None
+            };
+            if let Some(host_header) = route.host_header {
+                if parsed.protocol() == Protocol::Tcp {
+                    return Err(err!("'host_header' cannot be provided for {} routes", Protocol::Tcp))
+                }
+                parsed.options.host_header = host_header.parse()?;
+            }
+            if route.trust_forwarded_headers && parsed.protocol() == Protocol::Tcp {
+                return Err(err!("'trust_forwarded_headers' cannot be provided for {} routes", Protocol::Tcp))
+            }
+            parsed.options.trust_forwarded_headers = route.trust_forwarded_headers;
+            if parsed.protocol() == Protocol::Tcp && !(route.request_headers.is_empty() && route.response_headers.is_empty()) {
+                return Err(err!("'request_headers' and 'response_headers' cannot be provided for {} routes", Protocol::Tcp))
+            }
+            parsed.options.request_headers = route.request_headers.iter().map(|r| r.parse()).collect::<Result<_,_>>()?;
+            parsed.options.response_headers = route.response_headers.iter().map(|r| r.parse()).collect::<Result<_,_>>()?;
+            if let Some(cors) = route.cors {
+                if parsed.protocol() == Protocol::Tcp {
+                    return Err(err!("'cors' cannot be provided for {} routes", Protocol::Tcp))
+                }
+                parsed.options.cors = Some(Cors::new(&cors.origins, &cors.methods, &cors.headers, cors.credentials, cors.max_age)?);
+            }
+            Ok(parsed)
+        }).map_err(|e| {
+            err!("{}:{}: {}", name, line, e)
+        })?;
+        routes.push(parsed);
+    }
+
+    Ok(routes)
+}
+
+/// Given a byte offset into some string, return the line number it's on.
+fn line_number(contents: &str, offset: usize) -> usize {
+    contents[..offset].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::location::{ Balance };
+    use crate::routes::{ HostHeader };
+
+    #[test]
+    fn routes_can_be_loaded_from_config() {
+        let config = r#"
+            [[route]]
+            src = "8080"
+            dest = "./client/files"
+
+            [[route]]
+            src = "8080/api"
+            dest = "9090"
+
+            [[route]]
+            src = "8080/favicon.ico"
+            dest = "./favicon.ico"
+            exact = true
+
+            [[route]]
+            src = "8080/api/users"
+            dest = "statuscode://201"
+            methods = ["POST", "PUT"]
+            exact = true
+
+            [[route]]
+            src = "8080/assets"
+            dest = "./client/assets"
+            cache_control = "max-age=31536000, immutable"
+
+            [[route]]
+            src = "8080/app"
+            dest = "./client/app"
+            fallback = ["=./client/app/index.html"]
+
+            [[route]]
+            src = "8081"
+            dest = "9090"
+            fallback = ["9091"]
+            fallback_on = [404, 502]
+
+            [[route]]
+            src = "8083"
+            dest = "9094"
+            host_header = "preserve"
+            trust_forwarded_headers = true
+            request_headers = ["set Authorization: Bearer abc"]
+            response_headers = ["remove Server", "append Vary: Origin"]
+
+            [[route]]
+            src = "8084/api"
+            dest = "9095"
+            cors = { origins = ["http://localhost:3000"], methods = ["GET", "POST"], credentials = true, max_age = 600 }
+
+            [[route]]
+            src = "8082"
+            dest = "9092,9093"
+            balance = "least-connections"
+            max_fails = 1
+            health_check_interval = 5
+            health_check_path = "health"
+        "#;
+
+        let expected = vec![
+            routes::parse_route("8080", "./client/files").unwrap(),
+            routes::parse_route("8080/api", "9090").unwrap(),
+            routes::parse_route("=8080/favicon.ico", "./favicon.ico").unwrap(),
+            routes::parse_route("POST,PUT==8080/api/users", "statuscode://201").unwrap(),
+            {
+                let mut route = routes::parse_route("8080/assets", "./client/assets").unwrap();
+                route.options.cache_control = Some(HeaderValue::from_static("max-age=31536000, immutable"));
+                route
+            },
+            {
+                let mut route = routes::parse_route("8080/app", "./client/app").unwrap();
+                route.add_fallback("=./client/app/index.html").unwrap();
+                route
+            },
+            {
+                let mut route = routes::parse_route("8081", "9090").unwrap();
+                route.add_fallback("9091").unwrap();
+                route.options.fallback_on = vec![StatusCode::NOT_FOUND, StatusCode::BAD_GATEWAY];
+                route
+            },
+            {
+                let mut route = routes::parse_route("8083", "9094").unwrap();
+                route.options.host_header = HostHeader::Preserve;
+                route.options.trust_forwarded_headers = true;
+                route.options.request_headers = vec!["set Authorization: Bearer abc".parse().unwrap()];
+                route.options.response_headers = vec!["remove Server".parse().unwrap(), "append Vary: Origin".parse().unwrap()];
+                route
+            },
+            {
+                let mut route = routes::parse_route("8084/api", "9095").unwrap();
+                route.options.cors = Some(Cors {
+                    origins: vec!["http://localhost:3000".to_owned()],
+                    methods: vec![hyper::Method::GET, hyper::Method::POST],
+                    headers: vec![],
+                    credentials: true,
+                    max_age: Some(Duration::from_secs(600))
+                });
+                route
+            },
+            {
+                let mut route = routes::parse_route("8082", "9092,9093").unwrap();
+                route.options.balance = Balance::LeastConnections;
+                route.options.max_fails = 1;
+                route.options.health_check = Some(HealthCheck {
+                    interval: Duration::from_secs(5),
+                    path: "/health".to_owned()
+                });
+                route
+            },
+        ];
+
+        let routes = from_str(config, "weave.toml").unwrap();
+        assert_eq!(routes, expected);
+    }
+
+    #[test]
+    fn config_errors_have_line_context() {
+        let configs = vec![
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "9090"
+
+                [[route]]
+                src = "tcp://8080/foo"
+                dest = "9090"
+            "#, "weave.toml:7: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "statuscode://abc"
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "9090"
+                wibble = true
+            "#, "weave.toml: "),
+            (r#"
+                [[route]]
+                src = "https://localhost:8443"
+                dest = "9090"
+                tls_cert = "cert.pem"
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "9090"
+                tls_cert = "cert.pem"
+                tls_key = "key.pem"
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "./files"
+                cache_control = "no-cache\n"
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "./files"
+                fallback = ["tcp://9090"]
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "./files"
+                fallback = ["9090"]
+                fallback_on = [1000]
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "9090,9091"
+                balance = "fastest"
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "9090"
+                health_check_path = "/health"
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "tcp://8080"
+                dest = "9090"
+                health_check_interval = 5
+                health_check_path = "/health"
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "9090"
+                max_fails = 0
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "tcp://8080"
+                dest = "9090"
+                host_header = "preserve"
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "9090"
+                request_headers = ["set Authorization"]
+            "#, "weave.toml:3: "),
+            (r#"
+                [[route]]
+                src = "8080"
+                dest = "9090"
+                cors = { origins = ["localhost:3000"] }
+            "#, "weave.toml:3: "),
+        ];
+
+        for (config, prefix) in configs {
+            let err = from_str(config, "weave.toml").unwrap_err().to_string();
+            assert!(err.starts_with(prefix), "Error '{}' should start with '{}'", err, prefix);
+        }
+    }
+
+}
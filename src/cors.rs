@@ -0,0 +1,235 @@
+use std::time::Duration;
+use hyper::{ Body, Response, Method, StatusCode };
+use hyper::header::{ self, HeaderMap, HeaderName, HeaderValue };
+use crate::errors::{ Error };
+
+/// Which cross-origin requests a route allows, and how long browsers
+/// can remember that for.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Cors {
+    /// Origins that can make requests. If empty, any origin can.
+    pub origins: Vec<String>,
+    /// Methods that can be used. If empty, whichever is asked for is allowed.
+    pub methods: Vec<Method>,
+    /// Headers that can be sent. If empty, whichever are asked for are allowed.
+    pub headers: Vec<HeaderName>,
+    /// Allow cookies and other credentials to be sent.
+    pub credentials: bool,
+    /// How long preflight responses can be cached for.
+    pub max_age: Option<Duration>
+}
+
+impl Cors {
+    /// Parse a policy from lists of origins, methods and headers. An origin
+    /// of '*' is the same as not listing any.
+    pub fn new<S: AsRef<str>>(origins: &[S], methods: &[S], headers: &[S], credentials: bool, max_age: Option<u64>) -> Result<Cors, Error> {
+        let mut cors = Cors { credentials, max_age: max_age.map(Duration::from_secs), ..Cors::default() };
+        for origin in origins {
+            let origin = origin.as_ref().trim().trim_end_matches('/');
+            if origin == "*" {
+                cors.origins.clear();
+                break
+            }
+            if HeaderValue::from_str(origin).is_err() || !origin.contains("://") {
+                return Err(err!("'{}' is not a valid origin; expected eg 'http://localhost:3000'", origin))
+            }
+            cors.origins.push(origin.to_owned());
+        }
+        for method in methods {
+            let method = method.as_ref().trim().to_ascii_uppercase();
+            cors.methods.push(method.parse().map_err(|_| err!("'{}' is not a valid method", method))?);
+        }
+        for header in headers {
+            let header = header.as_ref().trim();
+            cors.headers.push(header.parse().map_err(|_| err!("'{}' is not a valid header name", header))?);
+        }
+        Ok(cors)
+    }
+
+    /// Respond to a preflight request. If the origin or method isn't allowed,
+    /// we respond without any CORS headers and the browser won't go ahead.
+    pub fn preflight(&self, req_headers: &HeaderMap) -> Response<Body> {
+        let mut res = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+        let headers = res.headers_mut();
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        headers.append(header::VARY, HeaderValue::from_static("Access-Control-Request-Method"));
+        headers.append(header::VARY, HeaderValue::from_static("Access-Control-Request-Headers"));
+
+        let method = match preflight_method(&Method::OPTIONS, req_headers) {
+            Some(method) => method,
+            None => return res
+        };
+        if !self.methods.is_empty() && !self.methods.contains(&method) {
+            return res
+        }
+        if !self.allow_origin(req_headers.get(header::ORIGIN), headers) {
+            return res
+        }
+
+        let methods = if self.methods.is_empty() {
+            method.to_string()
+        } else {
+            self.methods.iter().map(|m| m.as_str()).collect::<Vec<_>>().join(", ")
+        };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_str(&methods).unwrap());
+
+        let allow_headers = if self.headers.is_empty() {
+            req_headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS).cloned()
+        } else {
+            let names: Vec<_> = self.headers.iter().map(|h| h.as_str()).collect();
+            Some(HeaderValue::from_str(&names.join(", ")).unwrap())
+        };
+        if let Some(allow_headers) = allow_headers {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+        res
+    }
+
+    /// Add CORS headers to the response to a request from an allowed origin.
+    pub fn decorate(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        // The response may already vary on the origin (eg if it came from a server that handles CORS):
+        let varies_on_origin = headers.get_all(header::VARY).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .any(|name| name == "*" || name.eq_ignore_ascii_case("origin"));
+        if !varies_on_origin {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        self.allow_origin(origin, headers);
+    }
+
+    /// Set the headers that allow the origin given, returning false if it's not allowed.
+    fn allow_origin(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) -> bool {
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return false
+        };
+        let allowed = self.origins.is_empty()
+            || origin.to_str().is_ok_and(|o| self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(o)));
+        if !allowed {
+            return false
+        }
+        // Browsers don't accept '*' for requests with credentials:
+        if self.origins.is_empty() && !self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        }
+        if self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        true
+    }
+}
+
+/// If a request is a CORS preflight, return the method that it is asking to use.
+pub fn preflight_method(method: &Method, headers: &HeaderMap) -> Option<Method> {
+    if method != Method::OPTIONS || !headers.contains_key(header::ORIGIN) {
+        return None
+    }
+    headers.get(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|m| Method::from_bytes(m.as_bytes()).ok())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn preflight_headers(origin: &str, method: &str, headers: &str) -> HeaderMap {
+        let mut req_headers = HeaderMap::new();
+        req_headers.insert(header::ORIGIN, origin.parse().unwrap());
+        req_headers.insert(header::ACCESS_CONTROL_REQUEST_METHOD, method.parse().unwrap());
+        req_headers.insert(header::ACCESS_CONTROL_REQUEST_HEADERS, headers.parse().unwrap());
+        req_headers
+    }
+
+    #[test]
+    fn policies_can_be_parsed() {
+        let cors = Cors::new(&["http://localhost:3000/"], &["get", "POST"], &["Content-Type"], true, Some(60)).unwrap();
+        assert_eq!(cors, Cors {
+            origins: vec!["http://localhost:3000".to_owned()],
+            methods: vec![Method::GET, Method::POST],
+            headers: vec![header::CONTENT_TYPE],
+            credentials: true,
+            max_age: Some(Duration::from_secs(60))
+        });
+        assert_eq!(Cors::new(&["http://localhost:3000", "*"], &[], &[], false, None).unwrap().origins, Vec::<String>::new());
+        assert!(Cors::new(&["localhost:3000"], &[], &[], false, None).is_err());
+        assert!(Cors::new(&[], &["NOT A METHOD"], &[], false, None).is_err());
+        assert!(Cors::new(&[], &[], &["Not A Header"], false, None).is_err());
+    }
+
+    #[test]
+    fn preflights_reflect_what_is_asked_for_by_default() {
+        let cors = Cors::default();
+        let res = cors.preflight(&preflight_headers("http://localhost:3000", "PUT", "content-type, x-token"));
+        let headers = res.headers();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "PUT");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "content-type, x-token");
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_MAX_AGE));
+    }
+
+    #[test]
+    fn preflights_respect_the_policy() {
+        let cors = Cors::new(&["http://localhost:3000"], &["GET", "POST"], &["Content-Type"], true, Some(600)).unwrap();
+
+        let res = cors.preflight(&preflight_headers("http://localhost:3000", "POST", "x-token"));
+        let headers = res.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "http://localhost:3000");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let disallowed = vec![
+            preflight_headers("http://localhost:4000", "POST", "x-token"),
+            preflight_headers("http://localhost:3000", "DELETE", "x-token"),
+        ];
+        for req_headers in disallowed {
+            let res = cors.preflight(&req_headers);
+            assert!(!res.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+            assert!(!res.headers().contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+        }
+    }
+
+    #[test]
+    fn responses_are_decorated_for_allowed_origins() {
+        let any_with_credentials = Cors { credentials: true, ..Cors::default() };
+        let mut headers = HeaderMap::new();
+        any_with_credentials.decorate(Some(&HeaderValue::from_static("http://a.localhost")), &mut headers);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "http://a.localhost");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::VARY], "Origin");
+
+        let one_origin = Cors::new(&["http://a.localhost"], &[], &[], false, None).unwrap();
+        let mut headers = HeaderMap::new();
+        one_origin.decorate(Some(&HeaderValue::from_static("http://b.localhost")), &mut headers);
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        one_origin.decorate(None, &mut headers);
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(headers.get_all(header::VARY).iter().count(), 1);
+    }
+
+    #[test]
+    fn origin_is_only_added_to_vary_if_missing() {
+        let cors = Cors::default();
+        for (vary, expected) in [("Accept-Encoding", vec!["Accept-Encoding", "Origin"]), ("Accept-Encoding, origin", vec!["Accept-Encoding, origin"]), ("*", vec!["*"])] {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::VARY, HeaderValue::from_static(vary));
+            cors.decorate(None, &mut headers);
+            assert_eq!(headers.get_all(header::VARY).iter().collect::<Vec<_>>(), expected, "Vary: {}", vary);
+        }
+    }
+
+}
@@ -55,12 +55,23 @@ local folder:
 {example8a}
 {example8b}
 
+Serve a single page app from `./dist`, falling back to `./dist/index.html` for
+paths that don't exist so that the app can handle them:
+
+{example11a}
+{example11b}
+
 Return HTTP status codes for some paths:
 {example9}
 {example9b}
 {example9c}
 {example9d}
 
+Respond with a body given inline:
+{example12a}
+{example12b}
+{example12c}
+
 Declare routes that do nothing using \"nothing\" (can be useful for scripted use):
 {example10}
 
@@ -121,11 +132,21 @@ Declare routes that do nothing using \"nothing\" (can be useful for scripted use
 # http://localhost:8080/bar/api/foo => ./files/foo.json
 # http://localhost:8080/api/foo => No route matches this".white(),
 
+    example11a="weave 8080 to ./dist or =./dist/index.html".cyan(),
+    example11b="# Examples of routing given the above:
+# http://localhost:8080/ => ./dist/index.html
+# http://localhost:8080/assets/app.js => ./dist/assets/app.js
+# http://localhost:8080/dashboard/42 => ./dist/index.html".white(),
+
     example9="# Pick a specific status code (only valid HTTP status codes are allowed):".white(),
     example9b="weave 8080 to statuscode://403".cyan(),
     example9c="# The alias \"nothing\" returns a 404 Not Found status:".white(),
     example9d="weave 8080 to nothing".cyan(),
 
+    example12a="weave =8080/health to 'text://ok'".cyan(),
+    example12b="# A status from 2xx to 5xx can be given before the body (other numbers are left as body text):".white(),
+    example12c="weave 8080/api to 'json://503/{\"error\":\"down\"}'".cyan(),
+
     example10="weave nothing and 8080 to 9090".cyan()
 
     ))
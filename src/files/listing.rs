@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+use hyper::{ Body, Response, header, http::request };
+use percent_encoding::{ AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode, percent_decode_str };
+use serde_json::json;
+use tokio::fs;
+use super::meta;
+
+/// Characters that need encoding in a path segment (everything but the unreserved ones).
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// An entry in a directory listing.
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>
+}
+
+/// Respond with a listing of the directory at the path provided. This is an HTML
+/// page unless JSON is asked for. Links are built on top of the path that was
+/// requested, so that they work wherever the route is mounted. If this is the
+/// root directory of the route, there is no parent directory to link to.
+pub async fn serve(dir: &Path, req: &request::Parts, is_root: bool) -> io::Result<Response<Body>> {
+    let entries = read_entries(dir).await?;
+
+    // Links to entries are appended to the path that was requested:
+    let mut base = req.uri.path().to_owned();
+    if !base.ends_with('/') {
+        base.push('/');
+    }
+    let parent = if is_root { None } else { Some(parent_path(&base)) };
+    let href = |entry: &Entry| {
+        let slash = if entry.is_dir { "/" } else { "" };
+        format!("{}{}{}", base, utf8_percent_encode(&entry.name, PATH_SEGMENT), slash)
+    };
+    let title = percent_decode_str(&base).decode_utf8_lossy().into_owned();
+
+    let wants_json = req.headers.get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+
+    let (content_type, body) = if wants_json {
+        let entries: Vec<_> = entries.iter().map(|entry| json!({
+            "name": entry.name,
+            "type": if entry.is_dir { "directory" } else { "file" },
+            "size": if entry.is_dir { None } else { Some(entry.size) },
+            "modified": entry.modified.map(httpdate::fmt_http_date),
+            "url": href(entry)
+        })).collect();
+        let listing = json!({
+            "path": title,
+            "parent": parent,
+            "entries": entries
+        });
+        ("application/json", serde_json::to_string_pretty(&listing).unwrap())
+    } else {
+        let mut rows = String::new();
+        if let Some(parent) = &parent {
+            rows.push_str(&format!("<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>\n",
+                escape_html(parent)));
+        }
+        for entry in &entries {
+            let slash = if entry.is_dir { "/" } else { "" };
+            let size = if entry.is_dir {
+                "<td>-</td>".to_owned()
+            } else {
+                format!("<td title=\"{} bytes\">{}</td>", entry.size, human_size(entry.size))
+            };
+            let modified = entry.modified.map(httpdate::fmt_http_date).unwrap_or_default();
+            rows.push_str(&format!("<tr><td><a href=\"{}\">{}{}</a></td>{}<td>{}</td></tr>\n",
+                escape_html(&href(entry)), escape_html(&entry.name), slash, size, modified));
+        }
+        ("text/html; charset=utf-8", format!(include_str!("listing.html"),
+            title = escape_html(&title),
+            rows = rows))
+    };
+
+    Ok(Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .unwrap())
+}
+
+/// Read the entries in a directory, directories first and then sorted by name.
+/// Symlinks are followed, and entries we can't read the metadata of are skipped.
+async fn read_entries(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut read_dir = fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let metadata = match fs::metadata(entry.path()).await {
+            Ok(metadata) => metadata,
+            Err(_) => continue
+        };
+        entries.push(Entry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok()
+        });
+    }
+
+    // Leave out metadata files, which describe how to respond with the files next to them:
+    let files: HashSet<String> = entries.iter().filter(|entry| !entry.is_dir).map(|entry| entry.name.clone()).collect();
+    entries.retain(|entry| entry.is_dir || !meta::described_name(&entry.name).is_some_and(|name| files.contains(name)));
+
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// Given a path ending in '/', return the path of the parent directory.
+fn parent_path(path: &str) -> String {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rfind('/') {
+        Some(idx) => trimmed[..=idx].to_owned(),
+        None => "/".to_owned()
+    }
+}
+
+/// Describe a number of bytes in a human friendly way.
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes)
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Escape a string so that it can be placed in HTML text or attributes.
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::test_support::{ temp_dir, body_string };
+
+    #[test]
+    fn parent_paths() {
+        assert_eq!(parent_path("/files/sub/"), "/files/");
+        assert_eq!(parent_path("/files/"), "/");
+        assert_eq!(parent_path("/"), "/");
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MB");
+    }
+
+    #[tokio::test]
+    async fn links_are_relative_to_the_requested_path() {
+        let dir = temp_dir();
+        std::fs::create_dir(dir.path().join("sub dir")).unwrap();
+        std::fs::write(dir.path().join("a&b.txt"), "hello").unwrap();
+
+        let (parts, _) = hyper::Request::get("/files/stuff")
+            .header("Accept", "application/json")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let res = serve(dir.path(), &parts, false).await.unwrap();
+        let listing: serde_json::Value = serde_json::from_str(&body_string(res).await).unwrap();
+
+        assert_eq!(listing["path"], "/files/stuff/");
+        assert_eq!(listing["parent"], "/files/");
+        assert_eq!(listing["entries"][0]["name"], "sub dir");
+        assert_eq!(listing["entries"][0]["url"], "/files/stuff/sub%20dir/");
+        assert_eq!(listing["entries"][1]["name"], "a&b.txt");
+        assert_eq!(listing["entries"][1]["url"], "/files/stuff/a%26b.txt");
+        assert_eq!(listing["entries"][1]["size"], 5);
+    }
+
+    #[tokio::test]
+    async fn meta_files_are_left_out() {
+        let dir = temp_dir();
+        std::fs::write(dir.path().join("user.json"), "{}").unwrap();
+        std::fs::write(dir.path().join("user.json.meta"), "status = 500").unwrap();
+        std::fs::write(dir.path().join("notes.meta"), "hello").unwrap();
+
+        let (parts, _) = hyper::Request::get("/").header("Accept", "application/json").body(()).unwrap().into_parts();
+        let res = serve(dir.path(), &parts, true).await.unwrap();
+        let listing: serde_json::Value = serde_json::from_str(&body_string(res).await).unwrap();
+        let names: Vec<_> = listing["entries"].as_array().unwrap().iter().map(|entry| entry["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["notes.meta", "user.json"]);
+    }
+
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+use hyper::{ Body, Response, StatusCode, HeaderMap };
+use hyper::header::{ HeaderName, HeaderValue };
+use serde::Deserialize;
+use tokio::fs;
+use crate::errors::{ Error };
+
+/// How to respond with a file, read from a '.meta' file alongside it
+/// (so 'user.json' is described by 'user.json.meta').
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Meta {
+    /// Respond with this status rather than the usual one.
+    pub status: Option<StatusCode>,
+    /// Headers to add to the response, replacing any we'd otherwise send.
+    pub headers: HeaderMap,
+    /// Wait this long before responding.
+    pub delay: Option<Duration>
+}
+
+/// The TOML that a '.meta' file contains.
+#[derive(Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetaFile {
+    status: Option<u16>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// The delay in milliseconds.
+    delay: Option<u64>
+}
+
+impl Meta {
+    /// Parse the contents of a '.meta' file.
+    pub fn parse(contents: &str) -> Result<Meta, Error> {
+        let file: MetaFile = toml::from_str(contents)?;
+        let status = match file.status {
+            Some(code) => Some(StatusCode::from_u16(code).map_err(|_| err!("'{}' is not a valid status code", code))?),
+            None => None
+        };
+        let mut headers = HeaderMap::new();
+        for (name, value) in file.headers {
+            let name: HeaderName = name.parse().map_err(|_| err!("'{}' is not a valid header name", name))?;
+            let value: HeaderValue = value.parse().map_err(|_| err!("'{}' is not a valid header value", value))?;
+            headers.insert(name, value);
+        }
+        Ok(Meta { status, headers, delay: file.delay.map(Duration::from_millis) })
+    }
+
+    /// Load the metadata for the file at the path provided, if it has any.
+    pub async fn load(path: &Path) -> Result<Option<Meta>, Error> {
+        let meta_path = meta_path(path);
+        match fs::read_to_string(&meta_path).await {
+            Ok(contents) => Meta::parse(&contents)
+                .map(Some)
+                .map_err(|e| err!("Invalid metadata in '{}': {}", meta_path.to_string_lossy(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(err!("Could not read metadata file '{}': {}", meta_path.to_string_lossy(), e))
+        }
+    }
+
+    /// Wait for any delay, and then apply the status and headers to a response.
+    pub async fn apply(&self, mut res: Response<Body>) -> Response<Body> {
+        if let Some(delay) = self.delay {
+            tokio::time::delay_for(delay).await;
+        }
+        if let Some(status) = self.status {
+            *res.status_mut() = status;
+        }
+        for (name, value) in &self.headers {
+            res.headers_mut().insert(name, value.clone());
+        }
+        res
+    }
+}
+
+/// Is this the '.meta' file for another file? These describe how to respond with
+/// the other file, and aren't served themselves.
+pub async fn is_meta_file(path: &Path) -> bool {
+    let described = match path.file_name().and_then(|name| name.to_str()).and_then(described_name) {
+        Some(name) => path.with_file_name(name),
+        None => return false
+    };
+    fs::metadata(described).await.is_ok_and(|metadata| metadata.is_file())
+}
+
+/// The name of the file that a '.meta' file with the name given would describe.
+pub fn described_name(name: &str) -> Option<&str> {
+    name.strip_suffix(".meta").filter(|name| !name.is_empty())
+}
+
+fn meta_path(path: &Path) -> PathBuf {
+    let mut meta_path = path.as_os_str().to_owned();
+    meta_path.push(".meta");
+    meta_path.into()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn meta_files_can_be_parsed() {
+        let meta = Meta::parse(r#"
+            status = 503
+            delay = 250
+            [headers]
+            Content-Type = "application/problem+json"
+            Retry-After = "120"
+        "#).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/problem+json"));
+        headers.insert("retry-after", HeaderValue::from_static("120"));
+        assert_eq!(meta, Meta {
+            status: Some(StatusCode::SERVICE_UNAVAILABLE),
+            headers,
+            delay: Some(Duration::from_millis(250))
+        });
+
+        assert_eq!(Meta::parse("").unwrap(), Meta::default());
+        assert!(Meta::parse("status = 1000").is_err());
+        assert!(Meta::parse("[headers]\n\"Bad Name\" = \"value\"").is_err());
+        assert!(Meta::parse("statuscode = 404").is_err());
+    }
+
+}
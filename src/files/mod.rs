@@ -0,0 +1,584 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::Metadata;
+use std::io::{ self, SeekFrom };
+use std::ops::Range;
+use std::path::{ Path, PathBuf };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use hyper::{ Body, Response, HeaderMap, Method, header, http::request };
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use futures_util::stream;
+use crate::routes::{ RouteOptions };
+use crate::errors::{ Error };
+
+mod listing;
+mod meta;
+
+pub use meta::{ Meta };
+
+/// How much of a file we read into memory at a time when streaming it.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The most ranges we'll accept in a `Range` header. Headers asking for more
+/// are ignored, so that the whole file is sent once rather than in many parts.
+const MAX_RANGES: usize = 32;
+
+/// HTTP dates can't represent anything from the year 10000 onwards.
+const MAX_HTTP_DATE_SECS: u64 = 253_402_300_800;
+
+/// The files we'll look for if a path points to a directory.
+const INDEX_FILES: &[&str] = &["index.htm", "index.html"];
+
+/// A file that has been opened, ready to be served.
+pub struct OpenFile {
+    pub file: fs::File,
+    pub path: PathBuf,
+    pub metadata: Metadata
+}
+
+/// Serve the file at the path provided, or an index file if the path is a
+/// directory. The file is streamed back rather than being read into memory.
+/// If a `Range` header is provided, only the parts of the file asked for are sent,
+/// and if the client already has an up to date copy of the file, nothing is.
+/// The path tail is the part of the request path that the route didn't match.
+/// Files can have a '.meta' file alongside them to change the response.
+pub async fn serve(path: &Path, req: &request::Parts, options: &RouteOptions, path_tail: &str) -> Response<Body> {
+    let file = match open(path).await {
+        Ok(file) => file,
+        Err(e) => {
+            // Directories without an index file can be listed if the route allows it:
+            if options.directory_listing && is_dir(path).await {
+                let is_root = path_tail.split('/').all(|s| s.is_empty() || s == ".");
+                return match listing::serve(path, req, is_root).await {
+                    Ok(res) => res,
+                    Err(e) => not_found(path, e)
+                }
+            }
+            return not_found(path, e)
+        }
+    };
+
+    // Metadata files aren't content, so we pretend that they don't exist:
+    if meta::is_meta_file(&file.path).await {
+        return not_found(path, io::Error::new(io::ErrorKind::NotFound, "metadata files are not served"))
+    }
+
+    // If there's metadata for the file, a status it gives replaces any we'd pick:
+    let meta = match Meta::load(&file.path).await {
+        Ok(meta) => meta,
+        Err(e) => return invalid_meta(e)
+    };
+    match meta {
+        Some(meta) => meta.apply(respond(file, req, options, meta.status.is_none())).await,
+        None => respond(file, req, options, true)
+    }
+}
+
+/// Respond with a file that has been opened. If `conditional` is false, the whole
+/// file is sent regardless of any `Range` or conditional headers.
+fn respond(file: OpenFile, req: &request::Parts, options: &RouteOptions, conditional: bool) -> Response<Body> {
+    let len = file.metadata.len();
+    let mime = mime_guess::from_path(&file.path).first_or_octet_stream();
+    let validators = Validators::new(&file.metadata);
+
+    // Headers that are sent back regardless of what we respond with:
+    let mut res = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &validators.etag);
+    if let Some(last_modified) = validators.last_modified {
+        res = res.header(header::LAST_MODIFIED, httpdate::fmt_http_date(last_modified));
+    }
+    if let Some(cache_control) = &options.cache_control {
+        res = res.header(header::CACHE_CONTROL, cache_control);
+    }
+
+    // The client's cached copy is up to date, so there's no need to send it again:
+    let is_get_or_head = req.method == Method::GET || req.method == Method::HEAD;
+    if conditional && is_get_or_head && validators.not_modified(&req.headers) {
+        return res.status(304).body(Body::empty()).unwrap()
+    }
+
+    // Ranges are ignored unless this is a GET request, and the file hasn't
+    // changed since the client last saw it (if they've asked us to check):
+    let ranges = req.headers.get(header::RANGE)
+        .filter(|_| conditional && req.method == Method::GET && validators.range_allowed(&req.headers))
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| parse_ranges(range, len));
+
+    match ranges.as_deref() {
+        // No (valid) range was asked for, so send the whole file:
+        None => {
+            res.status(200)
+                .header(header::CONTENT_TYPE, mime.as_ref())
+                .header(header::CONTENT_LENGTH, len)
+                .body(body(file.file, vec![Segment::File(0..len)]))
+                .unwrap()
+        },
+        // None of the ranges asked for overlap the file:
+        Some([]) => {
+            res.status(416)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .body(Body::empty())
+                .unwrap()
+        },
+        // A single range is sent back as is:
+        Some([range]) => {
+            res.status(206)
+                .header(header::CONTENT_TYPE, mime.as_ref())
+                .header(header::CONTENT_LENGTH, range.end - range.start)
+                .header(header::CONTENT_RANGE, content_range(range, len))
+                .body(body(file.file, vec![Segment::File(range.clone())]))
+                .unwrap()
+        },
+        // Multiple ranges are each sent as a part of a multipart response:
+        Some(ranges) => {
+            let boundary = multipart_boundary();
+            let mut segments = vec![];
+            for (idx, range) in ranges.iter().enumerate() {
+                let part_headers = format!("{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    if idx == 0 { "" } else { "\r\n" },
+                    boundary,
+                    mime,
+                    content_range(range, len));
+                segments.push(Segment::Bytes(part_headers.into_bytes()));
+                segments.push(Segment::File(range.clone()));
+            }
+            segments.push(Segment::Bytes(format!("\r\n--{}--\r\n", boundary).into_bytes()));
+
+            let body_len: u64 = segments.iter().map(Segment::len).sum();
+            res.status(206)
+                .header(header::CONTENT_TYPE, format!("multipart/byteranges; boundary={}", boundary))
+                .header(header::CONTENT_LENGTH, body_len)
+                .body(body(file.file, segments))
+                .unwrap()
+        }
+    }
+}
+
+/// Respond to say that the path provided can't be served.
+pub fn not_found(path: &Path, e: io::Error) -> Response<Body> {
+    let msg = format!("Weave: Could not read file '{}': {}", path.to_string_lossy(), e);
+    Response::builder()
+        .status(404)
+        .body(Body::from(msg))
+        .unwrap()
+}
+
+/// Respond to say that a file's metadata couldn't be used.
+pub fn invalid_meta(e: Error) -> Response<Body> {
+    Response::builder()
+        .status(500)
+        .body(Body::from(format!("Weave: {}", e)))
+        .unwrap()
+}
+
+/// Is there a directory at the path provided?
+async fn is_dir(path: &Path) -> bool {
+    fs::metadata(path).await.is_ok_and(|metadata| metadata.is_dir())
+}
+
+/// Open the file at the path provided. If the path is a directory, we look
+/// for an index file in it instead. Each file is only opened once.
+pub async fn open(path: &Path) -> io::Result<OpenFile> {
+    let file = fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_dir() {
+        return Ok(OpenFile { file, path: path.to_owned(), metadata })
+    }
+
+    let mut last_err = None;
+    for index in INDEX_FILES {
+        let index_path = path.join(index);
+        match open_file(&index_path).await {
+            Ok(file) => return Ok(file),
+            Err(e) => last_err = Some(e)
+        }
+    }
+    Err(last_err.unwrap())
+}
+
+/// Open the file at the path provided, failing if it's not a file.
+async fn open_file(path: &Path) -> io::Result<OpenFile> {
+    let file = fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    if metadata.is_dir() {
+        return Err(io::Error::other("Is a directory"))
+    }
+    Ok(OpenFile { file, path: path.to_owned(), metadata })
+}
+
+/// Details derived from a file's metadata, which clients can use to check
+/// whether a copy of the file that they have cached is still up to date.
+struct Validators {
+    etag: String,
+    last_modified: Option<SystemTime>
+}
+
+impl Validators {
+    fn new(metadata: &Metadata) -> Validators {
+        // Only times that can be represented as HTTP dates are used:
+        let last_modified = metadata.modified().ok().filter(|time| {
+            time.duration_since(UNIX_EPOCH).is_ok_and(|d| d.as_secs() < MAX_HTTP_DATE_SECS)
+        });
+        let modified_nanos = last_modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", modified_nanos, metadata.len()),
+            last_modified
+        }
+    }
+
+    /// Is the copy of the file that the client has up to date? `If-None-Match`
+    /// takes precedence over `If-Modified-Since` if both are provided.
+    fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+            return if_none_match.to_str().is_ok_and(|tags| {
+                tags.trim() == "*" || tags.split(',').any(|tag| weak_etag_eq(tag, &self.etag))
+            })
+        }
+        match (header_date(headers, header::IF_MODIFIED_SINCE), self.last_modified) {
+            (Some(since), Some(modified)) => unix_secs(modified) <= unix_secs(since),
+            _ => false
+        }
+    }
+
+    /// Should a `Range` be honoured? If `If-Range` is provided, it's only honoured
+    /// if the ETag or date given matches the file exactly.
+    fn range_allowed(&self, headers: &HeaderMap) -> bool {
+        let if_range = match headers.get(header::IF_RANGE) {
+            Some(if_range) => if_range.to_str().unwrap_or("").trim(),
+            None => return true
+        };
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            return if_range == self.etag
+        }
+        match (header_date(headers, header::IF_RANGE), self.last_modified) {
+            (Some(date), Some(modified)) => unix_secs(modified) == unix_secs(date),
+            _ => false
+        }
+    }
+}
+
+/// Compare ETags, ignoring whether or not they are weak.
+fn weak_etag_eq(a: &str, b: &str) -> bool {
+    let a = a.trim();
+    let b = b.trim();
+    a.strip_prefix("W/").unwrap_or(a) == b.strip_prefix("W/").unwrap_or(b)
+}
+
+/// Parse a header containing an HTTP date.
+fn header_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    let value = headers.get(name)?.to_str().ok()?;
+    httpdate::parse_http_date(value.trim()).ok()
+}
+
+/// HTTP dates are only precise to the second.
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Parse the value of a `Range` header into the byte ranges of a file that
+/// it refers to. `None` is returned if the header is not valid or asks for too
+/// many ranges (in which case it should be ignored), and an empty list if none
+/// of the ranges overlap the file (in which case it can't be satisfied). Ranges
+/// that overlap or are adjacent are merged, so no byte is sent more than once.
+fn parse_ranges(header: &str, len: u64) -> Option<Vec<Range<u64>>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+
+    let mut ranges: Vec<Range<u64>> = vec![];
+    let mut seen_spec = false;
+    for (idx, spec) in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).enumerate() {
+        if idx == MAX_RANGES { return None }
+        seen_spec = true;
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+
+        let range = if first.is_empty() {
+            // "-N" asks for the last N bytes of the file:
+            let suffix_len: u64 = last.parse().ok()?;
+            len.saturating_sub(suffix_len)..len
+        } else {
+            // "N-" asks for everything from N, and "N-M" for bytes N to M inclusive:
+            let first: u64 = first.parse().ok()?;
+            let end = if last.is_empty() {
+                len
+            } else {
+                let last: u64 = last.parse().ok()?;
+                if last < first { return None }
+                cmp::min(last.saturating_add(1), len)
+            };
+            first..end
+        };
+
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if !seen_spec {
+        return None
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = cmp::max(last.end, range.end),
+            _ => merged.push(range)
+        }
+    }
+    Some(merged)
+}
+
+/// The value of a `Content-Range` header describing some range of a file.
+fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// A boundary to separate the parts of a multipart response. This should
+/// be unlikely to appear in the content itself.
+fn multipart_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("weave-{:x}", nanos)
+}
+
+/// Part of a response body, which is either some bytes or a range of a file.
+enum Segment {
+    Bytes(Vec<u8>),
+    File(Range<u64>)
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match self {
+            Segment::Bytes(bytes) => bytes.len() as u64,
+            Segment::File(range) => range.end - range.start
+        }
+    }
+}
+
+/// Stream the segments provided back as a response body, reading ranges
+/// from the file a chunk at a time.
+fn body(file: fs::File, segments: Vec<Segment>) -> Body {
+    let state = (file, VecDeque::from(segments), 0);
+    let chunks = stream::unfold(state, |(mut file, mut segments, mut remaining)| async move {
+        loop {
+            // Keep reading from the current range of the file until we're done with it:
+            if remaining > 0 {
+                let mut buf = vec![0; cmp::min(CHUNK_SIZE as u64, remaining) as usize];
+                return match file.read(&mut buf).await {
+                    // The file is shorter than we expected; nothing more to send:
+                    Ok(0) => None,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Some((Ok(buf), (file, segments, remaining - n as u64)))
+                    },
+                    Err(e) => Some((Err(e), (file, VecDeque::new(), 0)))
+                }
+            }
+            // Then move on to the next segment:
+            match segments.pop_front()? {
+                Segment::Bytes(bytes) => {
+                    return Some((Ok(bytes), (file, segments, 0)))
+                },
+                Segment::File(range) => {
+                    if let Err(e) = file.seek(SeekFrom::Start(range.start)).await {
+                        return Some((Err(e), (file, VecDeque::new(), 0)))
+                    }
+                    remaining = range.end - range.start;
+                }
+            }
+        }
+    });
+    Body::wrap_stream(chunks)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::test_support::{ temp_dir, body_string };
+    use hyper::header::HeaderValue;
+
+    /// Serve a file in response to a GET request with the headers provided.
+    async fn get(path: &Path, headers: &[(&str, &str)], options: &RouteOptions) -> Response<Body> {
+        let mut req = hyper::Request::get("/");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let (parts, _) = req.body(()).unwrap().into_parts();
+        serve(path, &parts, options, "").await
+    }
+
+    #[tokio::test]
+    async fn index_files_are_used_for_directories() {
+        let dir = temp_dir();
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        std::fs::create_dir(dir.path().join("html")).unwrap();
+        std::fs::write(dir.path().join("html/index.html"), "hello").unwrap();
+
+        assert_eq!(open(&dir.path().join("html")).await.unwrap().path, dir.path().join("html/index.html"));
+        assert!(open(&dir.path().join("empty")).await.is_err());
+        assert!(open(&dir.path().join("missing")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn files_are_streamed_in_full() {
+        let dir = temp_dir();
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 3 + 10).map(|n| n as u8).collect();
+        std::fs::write(dir.path().join("file.bin"), &contents).unwrap();
+
+        let res = get(&dir.path().join("file.bin"), &[], &RouteOptions::default()).await;
+        assert_eq!(res.headers()["Content-Length"], contents.len().to_string().as_str());
+        assert_eq!(res.headers()["Content-Type"], "application/octet-stream");
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&body[..], &contents[..]);
+    }
+
+    #[tokio::test]
+    async fn meta_files_change_the_response() {
+        let dir = temp_dir();
+        std::fs::write(dir.path().join("error.json"), r#"{"error": "oops"}"#).unwrap();
+        std::fs::write(dir.path().join("error.json.meta"), "status = 500\n[headers]\nX-Mock = \"yes\"").unwrap();
+        std::fs::write(dir.path().join("bad.txt"), "hello").unwrap();
+        std::fs::write(dir.path().join("bad.txt.meta"), "status = \"nope\"").unwrap();
+
+        // Ranges aren't used when a status is given:
+        let res = get(&dir.path().join("error.json"), &[("Range", "bytes=0-1")], &RouteOptions::default()).await;
+        assert_eq!(res.status(), 500);
+        assert_eq!(res.headers()["Content-Type"], "application/json");
+        assert_eq!(res.headers()["X-Mock"], "yes");
+        assert_eq!(body_string(res).await, r#"{"error": "oops"}"#);
+
+        let res = get(&dir.path().join("bad.txt"), &[], &RouteOptions::default()).await;
+        assert_eq!(res.status(), 500);
+        assert!(body_string(res).await.contains("bad.txt.meta"));
+    }
+
+    #[tokio::test]
+    async fn meta_files_are_not_served() {
+        let dir = temp_dir();
+        std::fs::write(dir.path().join("user.json"), "{}").unwrap();
+        std::fs::write(dir.path().join("user.json.meta"), "status = 500").unwrap();
+        std::fs::write(dir.path().join("notes.meta"), "hello").unwrap();
+
+        assert_eq!(get(&dir.path().join("user.json.meta"), &[], &RouteOptions::default()).await.status(), 404);
+        // Files that only look like metadata are served as usual:
+        let res = get(&dir.path().join("notes.meta"), &[], &RouteOptions::default()).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(body_string(res).await, "hello");
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn ranges_can_be_parsed() {
+        let too_many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        let cases = vec![
+            ("bytes=0-9", Some(vec![0..10])),
+            ("bytes=10-", Some(vec![10..100])),
+            ("bytes=-10", Some(vec![90..100])),
+            ("bytes=-1000", Some(vec![0..100])),
+            ("bytes=90-1000", Some(vec![90..100])),
+            ("bytes=0-0, 5-9,-1", Some(vec![0..1, 5..10, 99..100])),
+            // Ranges that don't overlap the file are dropped:
+            ("bytes=100-", Some(vec![])),
+            ("bytes=100-200,-0", Some(vec![])),
+            ("bytes=0-1,200-300", Some(vec![0..2])),
+            // Overlapping and adjacent ranges are merged:
+            ("bytes=0-,0-,0-", Some(vec![0..100])),
+            ("bytes=50-59,0-9,10-19,55-70", Some(vec![0..20, 50..71])),
+            // Invalid headers are ignored:
+            ("bytes=", None),
+            ("bytes=9-0", None),
+            ("bytes=a-b", None),
+            ("bytes=5", None),
+            ("lines=0-9", None),
+            // As are headers asking for too many ranges:
+            (&*too_many, None),
+        ];
+
+        for (header, expected) in cases {
+            assert_eq!(parse_ranges(header, 100), expected, "Parsing '{}'", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn ranges_of_files_can_be_served() {
+        let dir = temp_dir();
+        std::fs::write(dir.path().join("file.txt"), "0123456789").unwrap();
+
+        let path = dir.path().join("file.txt");
+        let opts = RouteOptions::default();
+
+        let res = get(&path, &[("Range", "bytes=2-4")], &opts).await;
+        assert_eq!(res.status(), 206);
+        assert_eq!(res.headers()["Content-Range"], "bytes 2-4/10");
+        assert_eq!(body_string(res).await, "234");
+
+        let res = get(&path, &[("Range", "bytes=20-")], &opts).await;
+        assert_eq!(res.status(), 416);
+        assert_eq!(res.headers()["Content-Range"], "bytes */10");
+
+        let res = get(&path, &[("Range", "bytes=0-1,-2")], &opts).await;
+        assert_eq!(res.status(), 206);
+        let content_type = res.headers()["Content-Type"].to_str().unwrap().to_owned();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap().to_owned();
+        let content_length: usize = res.headers()["Content-Length"].to_str().unwrap().parse().unwrap();
+        let body = body_string(res).await;
+        assert_eq!(body.len(), content_length);
+        assert_eq!(body, format!(
+            "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+             --{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+             --{b}--\r\n", b = boundary));
+    }
+
+    #[tokio::test]
+    async fn cached_files_are_not_sent_again() {
+        let dir = temp_dir();
+        let path = dir.path().join("file.txt");
+        std::fs::write(&path, "0123456789").unwrap();
+        let opts = RouteOptions {
+            cache_control: Some(HeaderValue::from_static("no-cache")),
+            ..RouteOptions::default()
+        };
+
+        let res = get(&path, &[], &opts).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["Cache-Control"], "no-cache");
+        let etag = res.headers()["ETag"].to_str().unwrap().to_owned();
+        let last_modified = res.headers()["Last-Modified"].to_str().unwrap().to_owned();
+
+        let res = get(&path, &[("If-None-Match", &*etag)], &opts).await;
+        assert_eq!(res.status(), 304);
+        assert_eq!(res.headers()["Cache-Control"], "no-cache");
+        assert_eq!(res.headers()["ETag"], &*etag);
+
+        let res = get(&path, &[("If-None-Match", &*format!("\"other\", W/{}", etag))], &opts).await;
+        assert_eq!(res.status(), 304);
+
+        let res = get(&path, &[("If-Modified-Since", &*last_modified)], &opts).await;
+        assert_eq!(res.status(), 304);
+
+        // If-None-Match takes precedence over If-Modified-Since:
+        let res = get(&path, &[("If-None-Match", "\"other\""), ("If-Modified-Since", &*last_modified)], &opts).await;
+        assert_eq!(res.status(), 200);
+
+        let res = get(&path, &[("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")], &opts).await;
+        assert_eq!(res.status(), 200);
+
+        // Ranges are only honoured if If-Range matches:
+        let res = get(&path, &[("Range", "bytes=0-1"), ("If-Range", &*etag)], &opts).await;
+        assert_eq!(res.status(), 206);
+        let res = get(&path, &[("Range", "bytes=0-1"), ("If-Range", &*last_modified)], &opts).await;
+        assert_eq!(res.status(), 206);
+        let res = get(&path, &[("Range", "bytes=0-1"), ("If-Range", "\"other\"")], &opts).await;
+        assert_eq!(res.status(), 200);
+    }
+
+}
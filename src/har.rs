@@ -0,0 +1,455 @@
+use std::io::{ self, SeekFrom };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, MutexGuard, PoisonError };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use futures_util::StreamExt;
+use hyper::{ Body, Request, Response, StatusCode, HeaderMap, header, body::HttpBody };
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use tokio::fs::File;
+use tokio::io::{ AsyncWriteExt };
+use tokio::sync::mpsc;
+use crate::errors::{ Error };
+use crate::proxy::{ self, ConnectionInfo };
+use crate::util::{ rfc3339 };
+
+use log::{ warn };
+
+/// Closes the list of entries and the log; each new entry is written over this.
+const SUFFIX: &str = "\n]}}\n";
+
+/// How many entries can be waiting to be written before we start dropping them.
+const QUEUE_SIZE: usize = 1024;
+
+/// Write every HTTP exchange to a HAR 1.2 file, so that it can be loaded into
+/// browser devtools (or anything else that understands the format).
+#[derive(Debug)]
+pub struct Har {
+    /// Entries are handed to a single task which writes them to the file.
+    entries: mpsc::Sender<Entry>,
+    /// Bodies are included up to this many bytes (0 leaves them out).
+    body_limit: usize
+}
+
+impl Har {
+    /// Create (or truncate) a HAR file at the path provided, and start
+    /// the task that writes entries to it.
+    pub async fn create(path: impl AsRef<Path>, body_limit: usize) -> Result<Har, Error> {
+        let path = path.as_ref().to_owned();
+        let creator = serde_json::json!({ "name": "weave", "version": env!("CARGO_PKG_VERSION") });
+        let header = format!("{{\"log\":{{\"version\":\"1.2\",\"creator\":{},\"entries\":[{}", creator, SUFFIX);
+        let mut file = File::create(&path).await
+            .map_err(|e| err!("could not create the HAR file '{}': {}", path.display(), e))?;
+        write_and_flush(&mut file, &header).await
+            .map_err(|e| err!("could not write to the HAR file '{}': {}", path.display(), e))?;
+
+        let (entries, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(write_entries(path, file, receiver));
+        Ok(Har { entries, body_limit })
+    }
+
+    /// Start recording an exchange, given the request (which is handed back, with its
+    /// body being captured as it's read) and the time that we started handling it.
+    pub fn start(self: &Arc<Self>, req: Request<Body>, conn_info: &ConnectionInfo, start: Instant) -> (Request<Body>, Exchange) {
+        let (parts, mut body) = req.into_parts();
+        let request_body = Arc::new(Mutex::new(Capture::new(self.body_limit)));
+
+        // The body of a request to switch protocols is needed to upgrade the
+        // connection, so we leave it alone:
+        if !proxy::is_upgrade(&parts) {
+            let capture = Arc::clone(&request_body);
+            body = Body::wrap_stream(body.map(move |chunk| {
+                if let Ok(chunk) = &chunk {
+                    lock(&capture).add(chunk);
+                }
+                chunk
+            }));
+        }
+
+        let scheme = if conn_info.secure { "https" } else { "http" };
+        let host = parts.uri.authority().map(|a| a.to_string())
+            .or_else(|| parts.headers.get(header::HOST).and_then(|h| h.to_str().ok()).map(|h| h.to_owned()))
+            .unwrap_or_else(|| format!("localhost:{}", conn_info.port));
+        let path_and_query = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+        let request = HarRequest {
+            method: parts.method.to_string(),
+            url: format!("{}://{}{}", scheme, host, path_and_query),
+            http_version: format!("{:?}", parts.version),
+            cookies: vec![],
+            headers: name_values(&parts.headers),
+            query_string: query_string(parts.uri.query().unwrap_or("")),
+            post_data: None,
+            headers_size: -1,
+            body_size: content_length(&parts.headers).unwrap_or(-1)
+        };
+        let exchange = Exchange {
+            entries: self.entries.clone(),
+            started: SystemTime::now(),
+            start,
+            request,
+            request_body,
+            response: None,
+            response_body: Capture::new(self.body_limit),
+            waited: Duration::from_secs(0)
+        };
+        (Request::from_parts(parts, body), exchange)
+    }
+}
+
+/// Write each entry that we're sent to the end of the HAR file, until every
+/// sender has gone away.
+async fn write_entries(path: PathBuf, mut file: File, mut entries: mpsc::Receiver<Entry>) {
+    let mut count = 0;
+    while let Some(entry) = entries.recv().await {
+        let json = serde_json::to_string(&entry).expect("HAR entries can always be serialized");
+        let separator = if count == 0 { "\n" } else { ",\n" };
+        let res = match file.seek(SeekFrom::End(-(SUFFIX.len() as i64))).await {
+            Ok(_) => write_and_flush(&mut file, &format!("{}{}{}", separator, json, SUFFIX)).await,
+            Err(e) => Err(e)
+        };
+        match res {
+            Ok(()) => count += 1,
+            Err(e) => warn!("could not write to the HAR file '{}': {}", path.display(), e)
+        }
+    }
+}
+
+async fn write_and_flush(file: &mut File, s: &str) -> io::Result<()> {
+    file.write_all(s.as_bytes()).await?;
+    file.flush().await
+}
+
+/// Captures are only ever added to, so one is still usable if a
+/// thread panicked while holding the lock.
+fn lock(capture: &Mutex<Capture>) -> MutexGuard<'_, Capture> {
+    capture.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// An exchange that's in progress. Its entry is handed over to be written to the
+/// HAR file once it's dropped, which happens when the response body has been sent
+/// (or the client has gone away).
+pub struct Exchange {
+    entries: mpsc::Sender<Entry>,
+    started: SystemTime,
+    start: Instant,
+    request: HarRequest,
+    request_body: Arc<Mutex<Capture>>,
+    response: Option<HarResponse>,
+    response_body: Capture,
+    /// How long we took to respond, not counting sending the response body.
+    waited: Duration
+}
+
+impl Exchange {
+    /// Note the response that we're sending back, capturing its body as it's sent.
+    pub fn finish(mut self, res: Response<Body>) -> Response<Body> {
+        self.waited = self.start.elapsed();
+        let (mut parts, body) = res.into_parts();
+        self.response = Some(HarResponse {
+            status: parts.status.as_u16(),
+            status_text: parts.status.canonical_reason().unwrap_or("").to_owned(),
+            http_version: format!("{:?}", parts.version),
+            cookies: vec![],
+            headers: name_values(&parts.headers),
+            content: Content::default(),
+            redirect_url: parts.headers.get(header::LOCATION).and_then(|h| h.to_str().ok()).unwrap_or("").to_owned(),
+            headers_size: -1,
+            body_size: 0
+        });
+
+        // There's nothing to wait for if there's no body, and the body of a response
+        // that switches protocols is left alone (the entry is written when we return):
+        let size = body.size_hint().exact();
+        if parts.status == StatusCode::SWITCHING_PROTOCOLS || size == Some(0) {
+            return Response::from_parts(parts, body)
+        }
+
+        // A wrapped body doesn't know its size, so we make sure that one isn't lost:
+        if let Some(size) = size {
+            parts.headers.entry(header::CONTENT_LENGTH).or_insert(size.into());
+        }
+        let mut exchange = self;
+        let body = Body::wrap_stream(body.map(move |chunk| {
+            if let Ok(chunk) = &chunk {
+                exchange.response_body.add(chunk);
+            }
+            chunk
+        }));
+        Response::from_parts(parts, body)
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        let mut response = match self.response.take() {
+            Some(response) => response,
+            None => return
+        };
+        let time = self.start.elapsed();
+
+        let request_body = lock(&self.request_body);
+        let mut request = self.request.clone();
+        if request.body_size < 0 {
+            request.body_size = request_body.size as i64;
+        }
+        if !request_body.bytes.is_empty() {
+            request.post_data = Some(PostData {
+                mime_type: header_str(&request.headers, "content-type"),
+                text: String::from_utf8_lossy(&request_body.bytes).into_owned(),
+                comment: request_body.truncated_comment()
+            });
+        }
+
+        let body = &self.response_body;
+        let (text, encoding) = if body.bytes.is_empty() { (None, None) } else { body_text(&body.bytes) };
+        response.body_size = body.size as i64;
+        response.content = Content {
+            size: body.size as i64,
+            mime_type: header_str(&response.headers, "content-type"),
+            text,
+            encoding,
+            comment: body.truncated_comment()
+        };
+
+        let since_epoch = self.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let started_date_time = format!("{}.{:03}Z",
+            rfc3339(since_epoch.as_secs()).trim_end_matches('Z'),
+            since_epoch.subsec_millis());
+
+        let entry = Entry {
+            started_date_time,
+            time: millis(time),
+            request,
+            response,
+            cache: serde_json::json!({}),
+            timings: Timings {
+                send: 0.0,
+                wait: millis(self.waited),
+                receive: millis(time.checked_sub(self.waited).unwrap_or_default())
+            }
+        };
+        // Rather than hold up requests, we drop entries if the file can't keep up:
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.entries.try_send(entry) {
+            warn!("dropped an entry for the HAR file, which isn't being written to quickly enough");
+        }
+    }
+}
+
+/// The start of a body (up to a limit), and its full size.
+#[derive(Debug)]
+struct Capture {
+    bytes: Vec<u8>,
+    size: usize,
+    limit: usize
+}
+
+impl Capture {
+    fn new(limit: usize) -> Capture {
+        Capture { bytes: vec![], size: 0, limit }
+    }
+    fn add(&mut self, chunk: &[u8]) {
+        let room = self.limit.saturating_sub(self.bytes.len());
+        self.bytes.extend_from_slice(&chunk[..room.min(chunk.len())]);
+        self.size += chunk.len();
+    }
+    fn truncated_comment(&self) -> Option<String> {
+        if self.size > self.bytes.len() && !self.bytes.is_empty() {
+            Some(format!("Truncated to the first {} of {} bytes", self.bytes.len(), self.size))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug,Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: f64,
+    request: HarRequest,
+    response: HarResponse,
+    cache: serde_json::Value,
+    timings: Timings
+}
+
+#[derive(Debug,Clone,Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    http_version: String,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    query_string: Vec<NameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<PostData>,
+    headers_size: i64,
+    body_size: i64
+}
+
+#[derive(Debug,Clone,Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PostData {
+    mime_type: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>
+}
+
+#[derive(Debug,Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarResponse {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64
+}
+
+#[derive(Debug,Default,Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: i64,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>
+}
+
+#[derive(Debug,Serialize)]
+struct Timings {
+    send: f64,
+    wait: f64,
+    receive: f64
+}
+
+#[derive(Debug,Clone,Serialize)]
+struct NameValue {
+    name: String,
+    value: String
+}
+
+fn name_values(headers: &HeaderMap) -> Vec<NameValue> {
+    headers.iter()
+        .map(|(name, value)| NameValue { name: name.to_string(), value: String::from_utf8_lossy(value.as_bytes()).into_owned() })
+        .collect()
+}
+
+fn header_str(headers: &[NameValue], name: &str) -> String {
+    headers.iter().find(|h| h.name == name).map(|h| h.value.clone()).unwrap_or_default()
+}
+
+fn content_length(headers: &HeaderMap) -> Option<i64> {
+    headers.get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+fn query_string(query: &str) -> Vec<NameValue> {
+    let decode = |s: &str| percent_decode_str(&s.replace('+', " ")).decode_utf8_lossy().into_owned();
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            NameValue { name: decode(name), value: decode(value) }
+        })
+        .collect()
+}
+
+/// Bodies are included as text where possible, and base64 encoded otherwise.
+fn body_text(bytes: &[u8]) -> (Option<String>, Option<String>) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (Some(text.to_owned()), None),
+        // Text that's been truncated part way through a character is still text:
+        Err(e) if e.error_len().is_none() => (Some(String::from_utf8_lossy(&bytes[..e.valid_up_to()]).into_owned()), None),
+        Err(_) => (Some(base64::encode(bytes)), Some("base64".to_owned()))
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::test_support::temp_dir;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn exchanges_are_written_to_the_har_file() {
+        let dir = temp_dir();
+        let path = dir.path().join("test.har");
+        let har = Arc::new(Har::create(&path, 4).await.unwrap());
+        let conn_info = ConnectionInfo { peer_addr: "127.0.0.1:1234".parse().unwrap(), port: 8080, secure: false };
+
+        for (body, status) in [("hello", 201), ("", 404)] {
+            let req = Request::builder()
+                .method("POST")
+                .uri("/api/users?name=Bob+Smith&x")
+                .header("host", "localhost:8080")
+                .body(Body::from(body))
+                .unwrap();
+            let (req, exchange) = har.start(req, &conn_info, Instant::now());
+            hyper::body::to_bytes(req.into_body()).await.unwrap();
+
+            let res = Response::builder().status(status).body(Body::from(body.to_uppercase())).unwrap();
+            let res = exchange.finish(res);
+            assert_eq!(res.headers().get("content-length").map(|h| h.to_str().unwrap().to_owned()),
+                       if body.is_empty() { None } else { Some(body.len().to_string()) });
+            assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), body.to_uppercase());
+        }
+
+        // Entries are written in the background, so wait for them to show up:
+        let mut har = Value::Null;
+        for _ in 0..100 {
+            har = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            if har["log"]["entries"].as_array().unwrap().len() == 2 {
+                break
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(har["log"]["version"], "1.2");
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let request = &entries[0]["request"];
+        assert_eq!(request["method"], "POST");
+        assert_eq!(request["url"], "http://localhost:8080/api/users?name=Bob+Smith&x");
+        assert_eq!(request["httpVersion"], "HTTP/1.1");
+        assert_eq!(request["queryString"], serde_json::json!([{"name": "name", "value": "Bob Smith"}, {"name": "x", "value": ""}]));
+        assert_eq!(request["bodySize"], 5);
+        assert_eq!(request["postData"]["text"], "hell");
+        assert_eq!(request["postData"]["comment"], "Truncated to the first 4 of 5 bytes");
+
+        let response = &entries[0]["response"];
+        assert_eq!(response["status"], 201);
+        assert_eq!(response["statusText"], "Created");
+        assert_eq!(response["content"]["size"], 5);
+        assert_eq!(response["content"]["text"], "HELL");
+
+        let empty = &entries[1];
+        assert_eq!(empty["response"]["status"], 404);
+        assert!(empty["request"].get("postData").is_none());
+        assert!(empty["response"]["content"].get("text").is_none());
+        assert!(empty["startedDateTime"].as_str().unwrap().ends_with('Z'));
+        assert!(empty["time"].as_f64().unwrap() >= empty["timings"]["wait"].as_f64().unwrap());
+    }
+
+    #[test]
+    fn bodies_are_text_where_possible() {
+        assert_eq!(body_text(b"hello"), (Some("hello".to_owned()), None));
+        // "é" cut off after its first byte:
+        assert_eq!(body_text(&[b'a', 0xc3]), (Some("a".to_owned()), None));
+        assert_eq!(body_text(&[0xff, 0x00]), (Some("/wA=".to_owned()), Some("base64".to_owned())));
+    }
+
+}
@@ -0,0 +1,166 @@
+use std::str::FromStr;
+use hyper::header::{ HeaderMap, HeaderName, HeaderValue };
+use crate::errors::{ Error };
+use crate::location::{ Matches, expand_str_with_matches };
+
+use log::{ warn };
+
+/// Something to do to the headers of a request or response. Values
+/// can contain `(variables)` captured by the route's source location.
+#[derive(Debug,Clone,PartialEq)]
+pub enum HeaderRule {
+    /// Replace any existing values of the header.
+    Set(HeaderName, String),
+    /// Add a value alongside any existing values of the header.
+    Append(HeaderName, String),
+    Remove(HeaderName),
+    /// Move the values of the first header to the second, replacing any already there.
+    Rename(HeaderName, HeaderName)
+}
+
+impl FromStr for HeaderRule {
+    type Err = Error;
+    /// Parse rules of the form 'set Name: value', 'append Name: value',
+    /// 'remove Name' or 'rename Name: NewName'.
+    fn from_str(s: &str) -> Result<HeaderRule, Error> {
+        let invalid = || err!("'{}' is not a valid header rule; expected one of 'set Name: value', \
+                               'append Name: value', 'remove Name' or 'rename Name: NewName'", s);
+        let (op, rest) = s.trim().split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let name = |name: &str| {
+            HeaderName::from_str(name.trim()).map_err(|_| err!("'{}' is not a valid header name", name.trim()))
+        };
+        let value = |value: &str| {
+            let value = value.trim();
+            HeaderValue::from_str(value).map_err(|_| err!("'{}' is not a valid header value", value))?;
+            Ok::<_, Error>(value.to_owned())
+        };
+
+        match &*op.to_ascii_lowercase() {
+            "set" => {
+                let (n, v) = rest.split_once(':').ok_or_else(invalid)?;
+                Ok(HeaderRule::Set(name(n)?, value(v)?))
+            },
+            "append" => {
+                let (n, v) = rest.split_once(':').ok_or_else(invalid)?;
+                Ok(HeaderRule::Append(name(n)?, value(v)?))
+            },
+            "remove" => {
+                Ok(HeaderRule::Remove(name(rest)?))
+            },
+            "rename" => {
+                let (from, to) = rest.split_once(':').ok_or_else(invalid)?;
+                Ok(HeaderRule::Rename(name(from)?, name(to)?))
+            },
+            _ => Err(invalid())
+        }
+    }
+}
+
+impl HeaderRule {
+    /// Substitute any variables that have been matched into the rule.
+    pub fn expand(&self, matches: &Matches) -> HeaderRule {
+        match self {
+            HeaderRule::Set(name, value) => HeaderRule::Set(name.clone(), expand_str_with_matches(matches, value).into_owned()),
+            HeaderRule::Append(name, value) => HeaderRule::Append(name.clone(), expand_str_with_matches(matches, value).into_owned()),
+            rule => rule.clone()
+        }
+    }
+}
+
+/// Apply each of the rules in turn to some headers.
+pub fn apply(rules: &[HeaderRule], headers: &mut HeaderMap) {
+    for rule in rules {
+        match rule {
+            HeaderRule::Set(name, value) => {
+                if let Some(value) = to_header_value(name, value) {
+                    headers.insert(name, value);
+                }
+            },
+            HeaderRule::Append(name, value) => {
+                if let Some(value) = to_header_value(name, value) {
+                    headers.append(name, value);
+                }
+            },
+            HeaderRule::Remove(name) => {
+                headers.remove(name);
+            },
+            HeaderRule::Rename(from, to) => {
+                let values: Vec<_> = headers.get_all(from).iter().cloned().collect();
+                if values.is_empty() {
+                    continue
+                }
+                headers.remove(from);
+                headers.remove(to);
+                for value in values {
+                    headers.append(to, value);
+                }
+            }
+        }
+    }
+}
+
+/// Values are checked when rules are parsed, but substituting variables in can
+/// make them invalid. If so, we skip the rule.
+fn to_header_value(name: &HeaderName, value: &str) -> Option<HeaderValue> {
+    match HeaderValue::from_str(value) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("not setting the '{}' header; '{}' is not a valid value", name, value);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn rule(s: &str) -> HeaderRule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn rules_can_be_parsed() {
+        assert_eq!(rule("set Authorization: Bearer abc"), HeaderRule::Set(HeaderName::from_static("authorization"), "Bearer abc".to_owned()));
+        assert_eq!(rule("APPEND Via:weave"), HeaderRule::Append(HeaderName::from_static("via"), "weave".to_owned()));
+        assert_eq!(rule("remove Cookie"), HeaderRule::Remove(HeaderName::from_static("cookie")));
+        assert_eq!(rule("rename X-Old: X-New"), HeaderRule::Rename(HeaderName::from_static("x-old"), HeaderName::from_static("x-new")));
+
+        let bad_rules = vec![
+            "set Authorization",
+            "remove",
+            "delete Cookie",
+            "rename X-Old",
+            "set Bad Name: value",
+            "remove Bad: Name",
+        ];
+        for r in bad_rules {
+            assert!(r.parse::<HeaderRule>().is_err(), "'{}' should not parse", r);
+        }
+    }
+
+    #[test]
+    fn rules_can_be_applied() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", HeaderValue::from_static("a=b"));
+        headers.append("x-old", HeaderValue::from_static("1"));
+        headers.append("x-old", HeaderValue::from_static("2"));
+        headers.insert("via", HeaderValue::from_static("proxy"));
+
+        apply(&[
+            rule("set Authorization: Bearer abc"),
+            rule("append Via: weave"),
+            rule("remove Cookie"),
+            rule("rename X-Old: X-New"),
+            rule("rename X-Missing: Authorization"),
+        ], &mut headers);
+
+        assert_eq!(headers["authorization"], "Bearer abc");
+        assert_eq!(headers.get_all("via").iter().collect::<Vec<_>>(), vec!["proxy", "weave"]);
+        assert_eq!(headers.get_all("x-new").iter().collect::<Vec<_>>(), vec!["1", "2"]);
+        assert!(!headers.contains_key("cookie"));
+        assert!(!headers.contains_key("x-old"));
+    }
+
+}
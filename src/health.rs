@@ -0,0 +1,67 @@
+use std::net::{ SocketAddr };
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use crate::routes::{ Route };
+use crate::client::{ HttpClient };
+
+/// The longest we'll wait for a probe to succeed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How to actively check that the destinations of a route are healthy.
+#[derive(Debug,Clone,PartialEq)]
+pub struct HealthCheck {
+    /// How often to probe each destination.
+    pub interval: Duration,
+    /// The path to request from HTTP destinations. TCP destinations are
+    /// probed by opening a connection to them.
+    pub path: String
+}
+
+/// Start probing the destinations of any routes that have a health check.
+/// Probing stops once the routes are no longer being served.
+pub fn spawn_checks(routes: &[Route], client: &HttpClient) {
+    for route in routes {
+        let check = match &route.options.health_check {
+            Some(check) => check,
+            None => continue
+        };
+        let max_fails = route.options.max_fails;
+        let wait = PROBE_TIMEOUT.min(check.interval);
+
+        for dest in std::iter::once(&route.dest).chain(&route.fallbacks) {
+            if let Some(host_bits) = dest.url_upstreams() {
+                let client = client.clone();
+                let path = check.path.clone();
+                host_bits.spawn_probes(check.interval, max_fails, move |host_bits| {
+                    probe_http(client.clone(), format!("{}{}", host_bits, path), wait)
+                });
+            }
+            if let Some(addrs) = dest.socket_addrs() {
+                addrs.spawn_probes(check.interval, max_fails, move |addr| {
+                    probe_tcp(addr, wait)
+                });
+            }
+        }
+    }
+}
+
+/// HTTP destinations are healthy if they respond with a 2xx or 3xx status.
+async fn probe_http(client: HttpClient, url: String, wait: Duration) -> Result<(), String> {
+    let uri = url.parse().map_err(|e| format!("'{}' is not a valid URL: {}", url, e))?;
+    match timeout(wait, client.get(uri)).await {
+        Err(_) => Err(format!("no response to health check within {:?}", wait)),
+        Ok(Err(e)) => Err(e.to_string()),
+        Ok(Ok(res)) if res.status().is_success() || res.status().is_redirection() => Ok(()),
+        Ok(Ok(res)) => Err(format!("health check responded with {}", res.status()))
+    }
+}
+
+/// TCP destinations are healthy if we can connect to them.
+async fn probe_tcp(addr: SocketAddr, wait: Duration) -> Result<(), String> {
+    match timeout(wait, TcpStream::connect(addr)).await {
+        Err(_) => Err(format!("could not connect within {:?}", wait)),
+        Ok(Err(e)) => Err(e.to_string()),
+        Ok(Ok(_)) => Ok(())
+    }
+}
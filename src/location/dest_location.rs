@@ -7,6 +7,7 @@ use std::net::{ SocketAddr };
 use crate::errors::{ Error };
 use super::src_location::{ SrcLocation, Matches };
 use super::utils::{ Protocol, SplitUrl, to_socket_addr };
+use super::upstreams::{ Upstreams };
 
 /// A Destination location. This is what a request can be rerouted to.
 /// On matching, we look at the pair of source and destination locations
@@ -17,10 +18,19 @@ pub struct DestLocation(DestLocationInner);
 
 #[derive(Debug,Clone,PartialEq,Eq)]
 pub enum DestLocationInner {
-    Url{ host_bits: String, path: String, query: String },
-    Socket { address: SocketAddr },
+    /// Each upstream is a protocol, host and port, all sharing the same path and query.
+    Url{ host_bits: Upstreams<String>, path: String, query: String },
+    Socket { addresses: Upstreams<SocketAddr> },
     HttpStatusCode { code: hyper::StatusCode },
-    FilePath(String)
+    /// Redirect to a URL (which can leave out the protocol, host and port to stay on
+    /// the same origin). The rest of the request path and query params are passed on
+    /// unless the redirect is exact.
+    Redirect { code: hyper::StatusCode, origin: String, path: String, query: String, exact: bool },
+    /// Respond with a body given inline, eg 'text://ok' or 'json://404/{"error":"(id) not found"}'.
+    Inline { code: hyper::StatusCode, kind: InlineKind, body: String },
+    /// The rest of the request path is appended to file paths unless they are exact.
+    /// Templates are rendered using details of the request rather than served as they are.
+    FilePath { path: String, exact: bool, template: bool }
 }
 
 impl DestLocation {
@@ -29,9 +39,35 @@ impl DestLocation {
     pub fn parse(original: impl AsRef<str>, src: &SrcLocation) -> Result<DestLocation, Error> {
         let input = original.as_ref().trim();
 
-        // Starts with a '.' or '/', so will assume it's a filepath:
-        if [Some('.'), Some(path::MAIN_SEPARATOR)].contains(&input.chars().next()) {
-            return Ok(DestLocation(DestLocationInner::FilePath(input.to_owned())));
+        // Starts with a '.' or '/', so will assume it's a filepath (or
+        // with 'template://' and then a filepath, if it's a template):
+        if let Some((path, template)) = parse_file_path_str(input)? {
+            return Ok(DestLocation(DestLocationInner::FilePath{ path: path.to_owned(), exact: false, template }));
+        }
+
+        // Starts with a '=', so the file path will be served (or redirected to) exactly as given:
+        if let Some(path) = input.strip_prefix('=') {
+            if let Some((path, template)) = parse_file_path_str(path)? {
+                return Ok(DestLocation(DestLocationInner::FilePath{ path: path.to_owned(), exact: true, template }));
+            }
+            if let Some(redirect) = parse_redirect_str(path) {
+                return parse_redirect(redirect, src, true);
+            }
+            return Err(err!("Only file paths, templates and redirects can be prefixed with '=' to use them exactly"));
+        }
+
+        // Starts with 'redirect://', so we'll respond with a redirect:
+        if let Some(redirect) = parse_redirect_str(input) {
+            return parse_redirect(redirect, src, false);
+        }
+
+        // Starts with eg 'text://', so we'll respond with the body given:
+        if let Some((kind, body)) = parse_inline_str(input) {
+            if !matches!(src.protocol(), Protocol::Http | Protocol::Https) {
+                return Err(err!("Only {} and {} sources can respond with {}:// bodies", Protocol::Http, Protocol::Https, kind))
+            }
+            let (code, body) = split_status_code(body).unwrap_or((hyper::StatusCode::OK, body));
+            return Ok(DestLocation(DestLocationInner::Inline{ code, kind, body: body.to_owned() }));
         }
 
         // Else, expect it to look like a URL (this normalises things as well,
@@ -40,154 +76,188 @@ impl DestLocation {
 
         // React based on the source protocol to form a desination location:
         match src_protocol {
-            Protocol::Https | Protocol::HttpStatusCode => {
+            Protocol::HttpStatusCode => {
                 // This should be checked when parsing the source location and so is probably an error
                 // if we get here, but for safety we do the check and return a reasonable message:
-                return Err(err!("The source protocol cannot be {} or {}", Protocol::Https, Protocol::HttpStatusCode))
+                Err(err!("The source protocol cannot be {}", Protocol::HttpStatusCode))
             },
-            Protocol::Http => {
+            Protocol::Http | Protocol::Https => {
                 // Is the destination a status code? Try parsing that first.
                 if let Some(statuscode_str) = parse_statuscode_str(input) {
                     let code = statuscode_str.parse()?;
                     return Ok(DestLocation(DestLocationInner::HttpStatusCode{ code }))
                 }
 
-                // Otherwise, assume that the destination is a valid URL..
-                let url = SplitUrl::parse(input)?;
-                let dest_protocol = url.protocol.unwrap_or(Protocol::Http);
-                if !&[Protocol::Http, Protocol::Https, Protocol::HttpStatusCode].contains(&dest_protocol) {
-                    return Err(err!("Given a source protocol of '{}', the destination protocol should be '{}', '{}' or '{}'",
-                                    src_protocol, Protocol::Http, Protocol::Https, Protocol::HttpStatusCode))
-                }
+                // Otherwise, assume that the destination is a valid URL (or a comma
+                // separated list of them, to balance requests across)..
+                let mut host_bits = vec![];
+                let mut path_and_query = None;
+                for part in split_list(input)? {
+                    let url = SplitUrl::parse(part)?;
+                    let dest_protocol = url.protocol.unwrap_or(Protocol::Http);
+                    if !&[Protocol::Http, Protocol::Https, Protocol::HttpStatusCode].contains(&dest_protocol) {
+                        return Err(err!("Given a source protocol of '{}', the destination protocol should be '{}', '{}' or '{}'",
+                                        src_protocol, Protocol::Http, Protocol::Https, Protocol::HttpStatusCode))
+                    }
 
-                let host_bits = if let Some(port) = url.port {
-                    format!("{}://{}:{}", dest_protocol, url.host, port)
-                } else {
-                    format!("{}://{}", dest_protocol, url.host)
-                };
+                    host_bits.push(if let Some(port) = url.port {
+                        format!("{}://{}:{}", dest_protocol, url.host, port)
+                    } else {
+                        format!("{}://{}", dest_protocol, url.host)
+                    });
+
+                    let this_path_and_query = (url.path.into_owned(), url.query.to_owned());
+                    match &path_and_query {
+                        None => path_and_query = Some(this_path_and_query),
+                        Some(first) => if *first != this_path_and_query {
+                            return Err(err!("Each destination in a list must have the same path and query string"))
+                        }
+                    }
+                }
 
+                let (path, query) = path_and_query.expect("a list always has at least one entry");
                 Ok(DestLocation(DestLocationInner::Url{
-                    host_bits, path: url.path.into_owned(), query: url.query.to_owned()
+                    host_bits: Upstreams::new(host_bits), path, query
                 }))
             },
             Protocol::Tcp => {
-                let url = SplitUrl::parse(input)?;
-                let dest_protocol = url.protocol.unwrap_or(src_protocol);
-                if dest_protocol != src_protocol {
-                    return Err(err!("The destination protocol should match the source protocol \
-                                     of '{}'", src_protocol))
-                }
-                if url.path != "/" {
-                    return Err(err!("The destination cannot have a path when the source protocol \
-                                     is '{}'", src_protocol))
-                }
-                if url.query != "" {
-                    return Err(err!("The destination cannot have a query string when the source \
-                                     protocol is '{}'", src_protocol))
-                }
+                // Connections can be balanced across a comma separated list of addresses:
+                let mut addresses = vec![];
+                for part in split_list(input)? {
+                    let url = SplitUrl::parse(part)?;
+                    let dest_protocol = url.protocol.unwrap_or(src_protocol);
+                    if dest_protocol != src_protocol {
+                        return Err(err!("The destination protocol should match the source protocol \
+                                         of '{}'", src_protocol))
+                    }
+                    if url.path != "/" {
+                        return Err(err!("The destination cannot have a path when the source protocol \
+                                         is '{}'", src_protocol))
+                    }
+                    if !url.query.is_empty() {
+                        return Err(err!("The destination cannot have a query string when the source \
+                                         protocol is '{}'", src_protocol))
+                    }
 
-                // Use the source port if a destination port isn't provided since
-                // it's the best hint that we have (and a not-unreasonable one):
-                let port = url.port.unwrap_or(src.port());
+                    // Use the source port if a destination port isn't provided since
+                    // it's the best hint that we have (and a not-unreasonable one):
+                    let port = url.port.unwrap_or(src.port());
 
-                let socket_addr = match to_socket_addr(&url.host, port) {
-                    Ok(addr) => addr,
-                    Err(e) => return Err(e)
-                };
+                    addresses.push(to_socket_addr(&url.host, port)?);
+                }
 
                 Ok(DestLocation(DestLocationInner::Socket {
-                    address: socket_addr
+                    addresses: Upstreams::new(addresses)
                 }))
             }
         }
     }
-    /// If the destination location is just a TCP socket address,
-    /// We can ask for it here.
-    pub fn socket_addr(&self) -> Option<SocketAddr> {
+    /// If the destination location is URLs, we can ask for the
+    /// protocol, host and port of each of them here.
+    pub fn url_upstreams(&self) -> Option<&Upstreams<String>> {
+        match &self.0 {
+            DestLocationInner::Url { host_bits, .. } => Some(host_bits),
+            _ => None
+        }
+    }
+    /// If the destination location is just TCP socket addresses,
+    /// We can ask for them here.
+    pub fn socket_addrs(&self) -> Option<&Upstreams<SocketAddr>> {
         match &self.0 {
-            DestLocationInner::Socket { address } => Some(*address),
+            DestLocationInner::Socket { addresses } => Some(addresses),
             _ => None
         }
     }
+    /// Share the balancing and health state of a previous destination, if it
+    /// has the same upstreams. Returns true if it did.
+    pub fn keep_state_from(&mut self, previous: &DestLocation) -> bool {
+        match (&mut self.0, &previous.0) {
+            (DestLocationInner::Url { host_bits, .. }, DestLocationInner::Url { host_bits: previous, .. }) => {
+                host_bits.keep_state_from(previous)
+            },
+            (DestLocationInner::Socket { addresses }, DestLocationInner::Socket { addresses: previous }) => {
+                addresses.keep_state_from(previous)
+            },
+            _ => false
+        }
+    }
     /// Output a resolved location given Matches from a source location.
     pub fn resolve(&self, matches: &Matches) -> ResolvedLocation {
         match &self.0 {
             DestLocationInner::Url{ host_bits, path, query } => {
-                // Substitute in matches (to the path+query params):
-                let mut path = expand_str_with_matches(matches, &path).into_owned();
-                let mut query = expand_str_with_matches(matches, &query).into_owned();
-
-                // Append the rest of the path onto the new URL:
-                let path_tail = matches.path_tail();
-                if !path_tail.is_empty() {
-                    if path.ends_with('/') {
-                        path.push_str(path_tail.trim_start_matches('/'));
-                    } else {
-                        if !path_tail.starts_with('/') { path.push('/'); }
-                        path.push_str(path_tail);
-                    }
-                }
+                let (path, query) = merge_path_and_query(matches, path, query);
 
-                // Append any query params that don't exist in the dest location already:
-                let query_copy = query.clone();
-                let current_query: Vec<_> = query_pairs(&query_copy).collect();
-                for (key, val) in query_pairs(matches.query()) {
-                    if current_query.iter().all(|(k,_)| k != &key) {
-                        if !query.is_empty() {
-                            query.push('&');
-                        }
-                        query.push_str(key);
-                        if !val.is_empty() {
-                            query.push('=');
-                            query.push_str(val);
-                        }
+                // Put everything together to get our final output URLs:
+                let urls = host_bits.map(|host_bits| {
+                    if query.is_empty() {
+                        format!("{}{}", host_bits, path)
+                    } else {
+                        format!("{}{}?{}", host_bits, path, query)
                     }
-                }
-
-                // Put everything together to get our final output URL:
-                let url = if query.is_empty() {
-                    format!("{}{}", host_bits, path)
-                } else {
-                    format!("{}{}?{}", host_bits, path, query)
-                };
-                ResolvedLocation::Url(url)
+                });
+                ResolvedLocation::Url(urls)
             },
-            DestLocationInner::FilePath(path) => {
+            DestLocationInner::FilePath{ path, exact, template } => {
                 // Substitute in matches (to any part of the path):
-                let mut path: PathBuf = expand_str_with_matches(matches, &path).into_owned().into();
+                let mut path: PathBuf = expand_str_with_matches(matches, path).into_owned().into();
 
                 // Append the rest of the path onto the new file path:
-                let bits = matches.path_tail().split('/').filter(|s| !s.is_empty());
-                let mut appended = 0;
-                for bit in bits {
-                    // Ignore bits that would do nothing:
-                    if bit == "." {
-                        continue
-                    }
-                    // Only allow going up in the path if we've gone down:
-                    else if bit == ".." {
-                        if appended > 0 {
-                            path.pop();
-                            appended -= 1;
+                if !*exact {
+                    let bits = matches.path_tail().split('/').filter(|s| !s.is_empty());
+                    let mut appended = 0;
+                    for bit in bits {
+                        // Ignore bits that would do nothing:
+                        if bit == "." {
+                            continue
+                        }
+                        // Only allow going up in the path if we've gone down:
+                        else if bit == ".." {
+                            if appended > 0 {
+                                path.pop();
+                                appended -= 1;
+                            }
+                        }
+                        // Append ordinary path pieces:
+                        else {
+                            path.push(bit);
+                            appended += 1;
                         }
-                    }
-                    // Append ordinary path pieces:
-                    else {
-                        path.push(bit);
-                        appended += 1;
                     }
                 }
 
-                ResolvedLocation::FilePath(path)
+                if *template {
+                    let captures = matches.iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect();
+                    ResolvedLocation::Template { path, captures }
+                } else {
+                    ResolvedLocation::FilePath(path)
+                }
             },
-            DestLocationInner::Socket{ address } => {
-                // If we are directed at a socket address, we have no matches to
-                // substitute so we just resolve it to a URL, assuming HTTP protocol:
-                ResolvedLocation::Url(format!("http://{}", address))
+            DestLocationInner::Socket{ addresses } => {
+                // If we are directed at socket addresses, we have no matches to
+                // substitute so we just resolve them to URLs, assuming HTTP protocol:
+                ResolvedLocation::Url(addresses.map(|address| format!("http://{}", address)))
             },
             DestLocationInner::HttpStatusCode{ code } => {
                 // Status code destinations just resolve to a code:
                 ResolvedLocation::HttpStatusCode(*code)
+            },
+            DestLocationInner::Inline{ code, kind, body } => {
+                let body = expand_str_with_matches(matches, body).into_owned();
+                ResolvedLocation::Inline { code: *code, kind: *kind, body }
+            },
+            DestLocationInner::Redirect{ code, origin, path, query, exact } => {
+                let origin = expand_str_with_matches(matches, origin);
+                let (path, query) = if *exact {
+                    (expand_str_with_matches(matches, path).into_owned(), expand_str_with_matches(matches, query).into_owned())
+                } else {
+                    merge_path_and_query(matches, path, query)
+                };
+                let location = if query.is_empty() {
+                    format!("{}{}", origin, path)
+                } else {
+                    format!("{}{}?{}", origin, path, query)
+                };
+                ResolvedLocation::Redirect { code: *code, location }
             }
         }
 
@@ -198,20 +268,36 @@ impl fmt::Display for DestLocation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.0 {
             DestLocationInner::Url{ host_bits, path, query } => {
-                if query.is_empty() {
-                    write!(f, "{}{}", host_bits, path)
-                } else {
-                    write!(f, "{}{}?{}", host_bits, path, query)
+                for (idx, host_bits) in host_bits.targets().iter().enumerate() {
+                    if idx > 0 { write!(f, ",")?; }
+                    if query.is_empty() {
+                        write!(f, "{}{}", host_bits, path)?;
+                    } else {
+                        write!(f, "{}{}?{}", host_bits, path, query)?;
+                    }
                 }
+                Ok(())
             },
-            DestLocationInner::FilePath(path) => {
+            DestLocationInner::FilePath{ path, exact, template } => {
+                if *exact { write!(f, "=")?; }
+                if *template { write!(f, "template://")?; }
                 path.fmt(f)
             },
             DestLocationInner::HttpStatusCode{ code } => {
                 write!(f, "statuscode://{}", code)
             }
-            DestLocationInner::Socket { address } => {
-                address.fmt(f)
+            DestLocationInner::Inline{ code, kind, body } => {
+                write!(f, "{}://{}/{}", kind, code.as_u16(), body)
+            }
+            DestLocationInner::Redirect{ code, origin, path, query, exact } => {
+                if *exact { write!(f, "=")?; }
+                write!(f, "redirect://{}/{}{}", code.as_u16(), origin, path)?;
+                if !query.is_empty() { write!(f, "?{}", query)?; }
+                Ok(())
+            }
+            DestLocationInner::Socket { addresses } => {
+                let addresses: Vec<_> = addresses.targets().iter().map(|a| a.to_string()).collect();
+                addresses.join(",").fmt(f)
             }
         }
     }
@@ -221,23 +307,64 @@ impl fmt::Display for DestLocation {
 /// looking at the source and destination locations provided.
 #[derive(Debug,Clone,PartialEq,Eq)]
 pub enum ResolvedLocation {
-    Url(String),
+    /// One or more URLs that requests are balanced across.
+    Url(Upstreams<String>),
     HttpStatusCode(hyper::StatusCode),
-    FilePath(PathBuf)
+    /// Respond with a redirect to the location given.
+    Redirect { code: hyper::StatusCode, location: String },
+    /// Respond with the body given.
+    Inline { code: hyper::StatusCode, kind: InlineKind, body: String },
+    FilePath(PathBuf),
+    /// Render the file at this path, which can use the variables captured from the request path.
+    Template { path: PathBuf, captures: Vec<(String, String)> }
 }
 
 impl fmt::Display for ResolvedLocation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            ResolvedLocation::Url(url) => url.fmt(f),
+            ResolvedLocation::Url(urls) => urls.targets().join(",").fmt(f),
             ResolvedLocation::FilePath(path) => path.to_string_lossy().fmt(f),
-            ResolvedLocation::HttpStatusCode(code) => write!(f, "statuscode://{}", code)
+            ResolvedLocation::Template{ path, .. } => write!(f, "template://{}", path.to_string_lossy()),
+            ResolvedLocation::HttpStatusCode(code) => write!(f, "statuscode://{}", code),
+            ResolvedLocation::Redirect{ code, location } => write!(f, "redirect://{}/{}", code.as_u16(), location),
+            ResolvedLocation::Inline{ code, kind, body } => write!(f, "{}://{}/{}", kind, code.as_u16(), body)
         }
     }
 }
 
+/// The kinds of body that can be given inline, which decide the content type sent with them.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum InlineKind {
+    Text,
+    Json,
+    Html
+}
+
+impl InlineKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InlineKind::Text => "text",
+            InlineKind::Json => "json",
+            InlineKind::Html => "html"
+        }
+    }
+    pub fn content_type(self) -> &'static str {
+        match self {
+            InlineKind::Text => "text/plain; charset=utf-8",
+            InlineKind::Json => "application/json",
+            InlineKind::Html => "text/html; charset=utf-8"
+        }
+    }
+}
+
+impl fmt::Display for InlineKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
 /// Given a str and some Matches, return a string with the matches substituted into it.
-fn expand_str_with_matches<'a>(matches: &Matches, s: &'a str) -> Cow<'a,str> {
+pub fn expand_str_with_matches<'a>(matches: &Matches, s: &'a str) -> Cow<'a,str> {
     lazy_static!{
         // Are we matching on parts of the path?
         static ref MATCH_NAME_RE: Regex = Regex::new(r"\(([a-zA-Z][a-zA-Z0-9_-]*)\)").expect("match_point_re");
@@ -254,8 +381,44 @@ fn expand_str_with_matches<'a>(matches: &Matches, s: &'a str) -> Cow<'a,str> {
     })
 }
 
+/// Substitute matches into a path and query, appending the rest of the request path
+/// to the path and any query params that the query doesn't already have.
+fn merge_path_and_query(matches: &Matches, path: &str, query: &str) -> (String, String) {
+    let mut path = expand_str_with_matches(matches, path).into_owned();
+    let mut query = expand_str_with_matches(matches, query).into_owned();
+
+    // Append the rest of the path onto the new URL:
+    let path_tail = matches.path_tail();
+    if !path_tail.is_empty() {
+        if path.ends_with('/') {
+            path.push_str(path_tail.trim_start_matches('/'));
+        } else {
+            if !path_tail.starts_with('/') { path.push('/'); }
+            path.push_str(path_tail);
+        }
+    }
+
+    // Append any query params that don't exist in the dest location already:
+    let query_copy = query.clone();
+    let current_query: Vec<_> = query_pairs(&query_copy).collect();
+    for (key, val) in query_pairs(matches.query()) {
+        if current_query.iter().all(|(k,_)| k != &key) {
+            if !query.is_empty() {
+                query.push('&');
+            }
+            query.push_str(key);
+            if !val.is_empty() {
+                query.push('=');
+                query.push_str(val);
+            }
+        }
+    }
+
+    (path, query)
+}
+
 /// Given a query fragment, return pairs of query params.
-fn query_pairs<'a>(query: &'a str) -> impl Iterator<Item=(&'a str, &'a str)> {
+fn query_pairs(query: &str) -> impl Iterator<Item=(&str, &str)> {
     query.split('&').filter(|part| !part.is_empty()).map(|part| {
         if let Some(mid) = part.find('=') {
             (&part[0..mid],&part[mid+1..])
@@ -265,17 +428,113 @@ fn query_pairs<'a>(query: &'a str) -> impl Iterator<Item=(&'a str, &'a str)> {
     })
 }
 
+/// Match a file path, or a file path following 'template://', noting whether it's a template.
+fn parse_file_path_str(s: &str) -> Result<Option<(&str, bool)>, Error> {
+    if is_file_path(s) {
+        return Ok(Some((s, false)))
+    }
+    match s.strip_prefix("template://") {
+        Some(path) if is_file_path(path) => Ok(Some((path, true))),
+        Some(_) => Err(err!("Templates should be file paths starting with '.' or '/', eg 'template://./mocks/user.json'")),
+        None => Ok(None)
+    }
+}
+
+/// Does the input look like a file path (starting with a '.' or '/')?
+fn is_file_path(s: &str) -> bool {
+    [Some('.'), Some(path::MAIN_SEPARATOR)].contains(&s.chars().next())
+}
+
+/// Split a comma separated list of destinations, none of which can be empty.
+fn split_list(s: &str) -> Result<Vec<&str>, Error> {
+    s.split(',').map(|part| {
+        let part = part.trim();
+        if part.is_empty() {
+            Err(err!("A comma separated list of destinations cannot contain empty entries"))
+        } else {
+            Ok(part)
+        }
+    }).collect()
+}
+
+/// Match a redirect://... input, handing back what comes after the protocol:
+fn parse_redirect_str(s: &str) -> Option<&str> {
+    s.strip_prefix("redirect://")
+}
+
+/// Parse what comes after 'redirect://'. This is an optional status code followed by
+/// a '/', and then either a URL with a protocol or a path starting with a '/' (eg
+/// 'https://example.com/new', '301/https://example.com/new' or '308//new').
+fn parse_redirect(s: &str, src: &SrcLocation, exact: bool) -> Result<DestLocation, Error> {
+    if !matches!(src.protocol(), Protocol::Http | Protocol::Https) {
+        return Err(err!("Only {} and {} sources can be redirected", Protocol::Http, Protocol::Https))
+    }
+
+    let (code, location) = match split_status_code(s) {
+        Some((code, location)) => {
+            if ![301, 302, 303, 307, 308].contains(&code.as_u16()) {
+                return Err(err!("'{}' is not a redirect status; expected one of 301, 302, 303, 307 or 308", code.as_u16()))
+            }
+            (code, location)
+        },
+        None => (hyper::StatusCode::FOUND, s)
+    };
+
+    // Split the location into an origin (which is empty to stay on the same one), path and query:
+    let (origin, path_and_query) = if location.starts_with('/') {
+        ("", location)
+    } else if let Some(idx) = location.find("://") {
+        let protocol = &location[..idx];
+        if protocol != "http" && protocol != "https" {
+            return Err(err!("Redirects should be to '{}' or '{}' URLs", Protocol::Http, Protocol::Https))
+        }
+        let rest = &location[idx+3..];
+        let origin_len = idx + 3 + rest.find(['/', '?']).unwrap_or(rest.len());
+        if origin_len == idx + 3 {
+            return Err(err!("The redirect location '{}' has no host", location))
+        }
+        (&location[..origin_len], &location[origin_len..])
+    } else {
+        return Err(err!("Redirects should be to a URL with a protocol (eg 'https://example.com/new') \
+                         or a path starting with '/'"))
+    };
+    let (path, query) = path_and_query.split_once('?').unwrap_or((path_and_query, ""));
+    let path = if path.is_empty() { "/" } else { path };
+
+    Ok(DestLocation(DestLocationInner::Redirect {
+        code,
+        origin: origin.to_owned(),
+        path: path.to_owned(),
+        query: query.to_owned(),
+        exact
+    }))
+}
+
+/// Split a status code followed by a '/' (eg '404/') from the start of a string, if there is one.
+/// Only 2xx to 5xx statuses are recognised, so that bodies can start with other numbers.
+fn split_status_code(s: &str) -> Option<(hyper::StatusCode, &str)> {
+    let (code, rest) = s.split_once('/')?;
+    let bytes = code.as_bytes();
+    if bytes.len() != 3 || !(b'2'..=b'5').contains(&bytes[0]) || !bytes.iter().all(|b| b.is_ascii_digit()) {
+        return None
+    }
+    Some((code.parse().ok()?, rest))
+}
+
+/// Match a text://, json:// or html:// input, handing back the kind and what comes after it.
+fn parse_inline_str(s: &str) -> Option<(InlineKind, &str)> {
+    [InlineKind::Text, InlineKind::Json, InlineKind::Html].iter().find_map(|&kind| {
+        s.strip_prefix(kind.as_str()).and_then(|s| s.strip_prefix("://")).map(|body| (kind, body))
+    })
+}
+
 /// Match a statuscode://123 or "nothing" input:
 fn parse_statuscode_str(s: &str) -> Option<&str> {
     if s == "nothing" {
         return Some("404")
     }
     static START: &str = "statuscode://";
-    if s.starts_with(START) {
-        Some(&s[START.len()..])
-    } else {
-        None
-    }
+    s.strip_prefix(START)
 }
 
 #[cfg(test)]
@@ -287,6 +546,17 @@ mod test {
         let src: SrcLocation = "http://localhost:1234".parse().unwrap();
         DestLocation::parse(u, &src).unwrap()
     }
+    fn redirect (n: u16, origin: &str, path: &str, query: &str, exact: bool) -> DestLocation {
+        let code = hyper::StatusCode::from_u16(n).unwrap();
+        DestLocation(DestLocationInner::Redirect{ code, origin: origin.to_owned(), path: path.to_owned(), query: query.to_owned(), exact })
+    }
+    fn inline (n: u16, kind: InlineKind, body: &str) -> DestLocation {
+        let code = hyper::StatusCode::from_u16(n).unwrap();
+        DestLocation(DestLocationInner::Inline{ code, kind, body: body.to_owned() })
+    }
+    fn template (path: &str, exact: bool) -> DestLocation {
+        DestLocation(DestLocationInner::FilePath{ path: path.to_owned(), exact, template: true })
+    }
     fn code (n: u16) -> DestLocation {
         DestLocation(DestLocationInner::HttpStatusCode{ code: hyper::StatusCode::from_u16(n).unwrap() })
     }
@@ -301,6 +571,8 @@ mod test {
             ("./foo/bar", u("./foo/bar")),
             // More Relative filepaths are ok:
             ("../foo/bar", u("../foo/bar")),
+            // Filepaths can be served exactly:
+            ("=./foo/bar.html", u("=./foo/bar.html")),
             // Just a port is OK
             ("8080", u("http://localhost:8080/")),
             // A port and path is OK
@@ -325,10 +597,31 @@ mod test {
             ("example.com", u("http://example.com/")),
             // Spaces either side will be ignored:
             ("  \t example.com\t \t", u("http://example.com/")),
+            // Several upstreams can be given:
+            ("8080,8081", u("http://localhost:8080/,http://localhost:8081/")),
+            ("8080/foo, https://example.com/foo", u("http://localhost:8080/foo,https://example.com/foo")),
             // Status code locations are ok:
             ("statuscode://404", code(404)),
             // Status code locations are ok:
             ("statuscode://204", code(204)),
+            // Redirects default to 302, and can be given a status:
+            ("redirect://https://example.com/new", redirect(302, "https://example.com", "/new", "", false)),
+            ("redirect://301/https://example.com:8080?a=b", redirect(301, "https://example.com:8080", "/", "a=b", false)),
+            // Redirects can stay on the same origin:
+            ("redirect:///new/(foo)", redirect(302, "", "/new/(foo)", "", false)),
+            ("=redirect://308//new", redirect(308, "", "/new", "", true)),
+            // Bodies can be given inline, with an optional status code:
+            ("text://ok", inline(200, InlineKind::Text, "ok")),
+            (r#"json://404/{"error": "(id) not found"}"#, inline(404, InlineKind::Json, r#"{"error": "(id) not found"}"#)),
+            ("html://<h1>a/b</h1>", inline(200, InlineKind::Html, "<h1>a/b</h1>")),
+            ("text://", inline(200, InlineKind::Text, "")),
+            // Only 2xx to 5xx are statuses; other numbers are part of the body:
+            ("text://100/x", inline(200, InlineKind::Text, "100/x")),
+            ("text://999/ok", inline(200, InlineKind::Text, "999/ok")),
+            ("text://200/404/x", inline(200, InlineKind::Text, "404/x")),
+            // Files can be rendered as templates:
+            ("template://./mocks/(name).json", template("./mocks/(name).json", false)),
+            ("=template:///mocks/user.json", template("/mocks/user.json", true)),
         ];
 
         for (actual, expected) in urls {
@@ -347,6 +640,19 @@ mod test {
             // Statuscode should be a number:
             "statuscode://abc",
             "statuscode://100/abc",
+            // Only filepaths can be exact:
+            "=8080/foo",
+            // Upstreams must share a path:
+            "8080/foo,8081/bar",
+            // And can't be empty:
+            "8080,",
+            // Redirects need a status that redirects, and a protocol or path:
+            "redirect://200/https://example.com",
+            "redirect://example.com/new",
+            "redirect://ftp://example.com/new",
+            "redirect://https://",
+            // Templates must be file paths:
+            "template://8080/foo",
         ];
 
         for actual in urls {
@@ -367,8 +673,11 @@ mod test {
             (VALID, "tcp://localhost:22", "2222"), // assume localhost for dest if not given
             (VALID, "http://localhost:22", "2222"), // assume localhost for dest if not given
             (VALID, "tcp://localhost:22", "localhost"), // assume same port as src if not given
+            (VALID, "tcp://localhost:22", "2222,2223"), // several destinations can be given
+            (INVALID, "tcp://localhost:22", "2222,2223/foo"), // no paths allowed on TCP
             (VALID, "http://localhost", "localhost:2222"),
-            (INVALID, "https://localhost", "localhost:2222"), // https is not a valid src protocol
+            (VALID, "https://localhost", "localhost:2222"), // https sources are served using TLS
+            (INVALID, "https://localhost", "tcp://localhost:2222"), // protocol mismatch
             (INVALID, "tcp://localhost", "localhost:22"), // src needs port if TCP
             (INVALID, "tcp://127.0.0.1:2222", "http://localhost"), // protocol mismatch
             (INVALID, "http://127.0.0.1:2222", "tcp://localhost"), // protocol mismatch
@@ -378,6 +687,10 @@ mod test {
             (INVALID, "tcp://127.0.0.1:2222", "statuscode://123"), // TCP cannot route to a statuscode
             (VALID, "http://127.0.0.1:2222", "nothing"), // HTTP can route to nothing (statuscode 404)
             (INVALID, "tcp://127.0.0.1:2222", "nothing"), // What would "nothing" mean for TCP?
+            (VALID, "http://127.0.0.1:2222", "redirect://https://example.com"), // HTTP can redirect
+            (INVALID, "tcp://127.0.0.1:2222", "redirect://https://example.com"), // TCP can't
+            (VALID, "http://127.0.0.1:2222", "json://{}"), // HTTP can respond with an inline body
+            (INVALID, "tcp://127.0.0.1:2222", "text://ok"), // TCP can't
         ];
 
         for (is_valid, src, dest) in routes {
@@ -385,7 +698,7 @@ mod test {
                 Ok(src) => src,
                 Err(e) => {
                     if is_valid {
-                        assert!(true, "{} should be valid src but got error: {}", src, e);
+                        panic!("{} should be valid src but got error: {}", src, e);
                     }
                     continue
                 }
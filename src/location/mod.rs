@@ -1,7 +1,9 @@
 mod src_location;
 mod dest_location;
 mod utils;
+mod upstreams;
 
 pub use src_location::*;
 pub use dest_location::*;
 pub use utils::Protocol;
+pub use upstreams::{ Upstreams, Balance, ActiveGuard };
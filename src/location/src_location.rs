@@ -1,4 +1,4 @@
-use hyper::Uri;
+use hyper::{ Uri, Method };
 use lazy_static::lazy_static;
 use regex::Regex;
 use url::Host;
@@ -18,12 +18,16 @@ pub struct SrcLocation {
     protocol: Protocol,
     /// Host
     host: Host<String>,
+    /// Match on the host of incoming requests using this, if provided
+    host_match: Option<HostMatch>,
     /// Port
     port: u16,
     /// Raw path as entered, for display purposes
     path: String,
     /// Match on paths using this regex
     path_regex: Regex,
+    /// Only match requests using these methods (any method if empty)
+    methods: Vec<Method>,
     /// Do we want this to be for exact matches only?
     exact: bool,
     /// Does this path have patterns in?
@@ -35,11 +39,13 @@ impl SrcLocation {
     pub fn parse(original: impl AsRef<str>) -> Result<SrcLocation, Error> {
         let input: &str = original.as_ref();
 
+        // Does the input begin with eg "GET,HEAD="? Only match those methods if so:
+        let (methods, input) = parse_methods(input)?;
+
         // Does the input begin with "="? Exact matches only if it does
-        let (exact, input) = if input.starts_with('=') {
-            (true, &input[1..])
-        } else {
-            (false, input)
+        let (exact, input) = match input.strip_prefix('=') {
+            Some(rest) => (true, rest),
+            None => (false, input)
         };
 
         // Split the URL into pieces:
@@ -49,6 +55,9 @@ impl SrcLocation {
         // Decide what to do based on the protocol:
         match protocol {
             Protocol::Tcp => {
+                if !methods.is_empty() {
+                    return Err(err!("Methods cannot be provided when specifying a {} route", protocol))
+                }
                 if path != "/" {
                     return Err(err!("A path cannot be provided when specifying a {} route", protocol))
                 }
@@ -58,35 +67,40 @@ impl SrcLocation {
                 Ok(SrcLocation {
                     protocol,
                     host,
+                    host_match: None,
                     path: String::new(),
                     port: port.unwrap(),
                     path_regex: Regex::new("").unwrap(),
+                    methods,
                     exact: true,
                     has_patterns: false
                 })
             },
-            Protocol::Http => {
+            Protocol::Http | Protocol::Https => {
                 // Parse the path into pieces to build a regex from:
                 let path_pieces = parse_path(&path);
                 // Did we find any patterns?
-                let has_patterns = path_pieces.iter().any(|p| if let PathPiece::Pattern{..} = p { true } else { false });
+                let has_patterns = path_pieces.iter().any(|p| matches!(p, PathPiece::Pattern{..}));
                 // Make the regex:
                 let path_regex = convert_path_pieces_to_regex(path_pieces, exact);
+                // Match on the host of incoming requests if a domain was given:
+                let host_match = HostMatch::from_host(&host)?;
 
                 // and hand this all back:
+                let default_port = if protocol == Protocol::Https { 443 } else { 80 };
+
                 Ok(SrcLocation {
-                    protocol: Protocol::Http,
+                    protocol,
                     host,
+                    host_match,
                     path: path.into_owned(),
-                    port: port.unwrap_or(80),
+                    port: port.unwrap_or(default_port),
                     path_regex,
+                    methods,
                     exact,
                     has_patterns
                 })
             },
-            Protocol::Https => {
-                Err(err!("'https' can be provided as the destination protocol but not as a source protocol"))
-            }
             Protocol::HttpStatusCode => {
                 Err(err!("'statuscode' can be provided as the destination protocol but not as a source protocol"))
             }
@@ -96,13 +110,25 @@ impl SrcLocation {
     pub fn protocol(&self) -> Protocol {
         self.protocol
     }
+    /// What host is this route using.
+    pub fn host(&self) -> &Host<String> {
+        &self.host
+    }
     /// What port is this route using.
     pub fn port(&self) -> u16 {
         self.port
     }
     /// Match an incoming request and give back a map of key->value pairs
-    /// found in performing the match.
-    pub fn match_uri<'a, 'b: 'a>(&'a self, uri: &'b Uri) -> Option<Matches<'a>> {
+    /// found in performing the match. The host is expected to be the value
+    /// of the request's Host header (a port is allowed), if there is one.
+    pub fn match_uri<'a, 'b: 'a>(&'a self, host: Option<&str>, uri: &'b Uri) -> Option<Matches<'a>> {
+
+        // If we care about the host, it must match first:
+        if let Some(host_match) = &self.host_match {
+            if !host.is_some_and(|host| host_match.matches(host)) {
+                return None
+            }
+        }
 
         let request_path = uri.path();
         let request_query = uri.query().unwrap_or("");
@@ -111,6 +137,7 @@ impl SrcLocation {
         if let Some(captures) = self.path_regex.captures(request_path) {
             let path_tail = &request_path[ captures.get(0).unwrap().end().. ];
             Some(Matches {
+                regex: &self.path_regex,
                 captures,
                 path_tail,
                 query: request_query
@@ -122,21 +149,47 @@ impl SrcLocation {
         }
 
     }
+    /// The methods that this route is limited to (any method if empty).
+    pub fn methods(&self) -> &[Method] {
+        &self.methods
+    }
+    /// Does this route accept requests using the method provided?
+    pub fn matches_method(&self, method: &Method) -> bool {
+        self.methods.is_empty() || self.methods.contains(method)
+    }
     /// Hand back a socket address that we can listen on for this route.
     pub fn to_socket_addr(&self) -> Result<SocketAddr, Error> {
-        to_socket_addr(&self.host, self.port)
+        // Wildcard hosts listen on the address of the domain they are
+        // a wildcard for, and (as per RFC 6761) anything ending in
+        // ".localhost" listens on localhost:
+        match &self.host {
+            Host::Domain(domain) => {
+                let domain = domain.trim_start_matches("*.");
+                let domain = if domain.ends_with(".localhost") { "localhost" } else { domain };
+                to_socket_addr(&Host::Domain(domain.to_owned()), self.port)
+            },
+            host => to_socket_addr(host, self.port)
+        }
     }
 }
 
 // Ordering:
-// 1. basic exact match (longest first)
-// 2. regex exact match (in order declared)
-// 3. basic prefix (longest first)
-// 4. regex prefix (in order declared)
+// 1. exact host, then wildcard host (longest first), then any host
+// 2. basic exact match (longest first)
+// 3. regex exact match (in order declared)
+// 4. basic prefix (longest first)
+// 5. regex prefix (in order declared)
 impl Ord for SrcLocation {
     fn cmp(&self, other: &Self) -> Ordering {
-        // Put all exact matching routes first:
-        self.exact.cmp(&other.exact).reverse().then_with(|| {
+        // Put routes that match on more specific hosts first:
+        let host_cmp = match (&self.host_match, &other.host_match) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None)    => Ordering::Less,
+            (None, Some(_))    => Ordering::Greater,
+            (None, None)       => Ordering::Equal
+        };
+        // Then put all exact matching routes first:
+        host_cmp.then_with(|| self.exact.cmp(&other.exact).reverse()).then_with(|| {
             match (self.has_patterns, other.has_patterns) {
                 // If regex, put that last, but maintain
                 // ordering within regex'd paths:
@@ -151,6 +204,10 @@ impl Ord for SrcLocation {
                         .reverse()
                 }
             }
+        }).then_with(|| {
+            // Routes limited to some methods are more specific than
+            // those that aren't, but are otherwise the same:
+            self.methods.is_empty().cmp(&other.methods.is_empty())
         })
     }
 }
@@ -163,8 +220,10 @@ impl PartialOrd for SrcLocation {
 
 impl PartialEq for SrcLocation {
     fn eq(&self, other: &Self) -> bool {
+        self.protocol == other.protocol &&
         self.host == other.host &&
         self.port == other.port &&
+        self.methods == other.methods &&
         self.exact == other.exact &&
         self.has_patterns == other.has_patterns &&
         self.path_regex.as_str() == other.path_regex.as_str()
@@ -175,13 +234,18 @@ impl Eq for SrcLocation { }
 impl FromStr for SrcLocation {
     type Err = Error;
     fn from_str(input: &str) -> Result<Self, Self::Err> {
-        SrcLocation::parse(input.to_owned())
+        SrcLocation::parse(input)
     }
 }
 
 impl fmt::Display for SrcLocation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.port == 80 {
+        if !self.methods.is_empty() {
+            let methods: Vec<&str> = self.methods.iter().map(|m| m.as_str()).collect();
+            write!(f, "{} ", methods.join(","))?;
+        }
+        let default_port = if self.protocol == Protocol::Https { 443 } else { 80 };
+        if self.port == default_port {
             write!(f, "{}://{}{}", self.protocol, self.host, self.path)
         } else {
             write!(f, "{}://{}:{}{}", self.protocol, self.host, self.port, self.path)
@@ -189,8 +253,94 @@ impl fmt::Display for SrcLocation {
     }
 }
 
+/// Split any methods, eg "GET,HEAD=", from the start of a source location.
+fn parse_methods(input: &str) -> Result<(Vec<Method>, &str), Error> {
+    lazy_static!{
+        static ref METHODS_RE: Regex = Regex::new(r"^([a-zA-Z]+(?:,[a-zA-Z]+)*)=").expect("methods_re");
+    }
+
+    let caps = match METHODS_RE.captures(input) {
+        Some(caps) => caps,
+        None => return Ok((vec![], input))
+    };
+
+    let mut methods = vec![];
+    for method in caps.get(1).unwrap().as_str().split(',') {
+        let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+            .map_err(|_| err!("'{}' is not a valid HTTP method", method))?;
+        if !methods.contains(&method) {
+            methods.push(method);
+        }
+    }
+    Ok((methods, &input[caps.get(0).unwrap().end()..]))
+}
+
+/// Which hosts a source location will match incoming requests on.
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum HostMatch {
+    /// Match exactly this host, eg `api.localhost`
+    Exact(String),
+    /// Match any subdomain of this host, eg `*.localhost`. The
+    /// leading '*' is stripped off, leaving eg `.localhost`.
+    Wildcard(String)
+}
+
+impl HostMatch {
+    /// Domains are matched on, with the exception of "localhost", which has
+    /// always been used to listen on localhost and accept requests for any host.
+    fn from_host(host: &Host<String>) -> Result<Option<HostMatch>, Error> {
+        let domain = match host {
+            Host::Domain(domain) if domain != "localhost" => domain,
+            _ => return Ok(None)
+        };
+        if let Some(suffix) = domain.strip_prefix('*') {
+            if !suffix.starts_with('.') || suffix.contains('*') {
+                return Err(err!("A wildcard can only be used at the start of a host, eg '*.localhost'"))
+            }
+            Ok(Some(HostMatch::Wildcard(suffix.to_owned())))
+        } else if domain.contains('*') {
+            Err(err!("A wildcard can only be used at the start of a host, eg '*.localhost'"))
+        } else {
+            Ok(Some(HostMatch::Exact(domain.to_owned())))
+        }
+    }
+    /// Does the host (which may include a port) match?
+    fn matches(&self, host: &str) -> bool {
+        let host = strip_port(host).trim_end_matches('.').to_ascii_lowercase();
+        match self {
+            HostMatch::Exact(domain) => host == *domain,
+            HostMatch::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(&**suffix)
+        }
+    }
+}
+
+// Exact hosts come first, and then wildcards (longest first):
+impl Ord for HostMatch {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (HostMatch::Exact(_), HostMatch::Exact(_)) => Ordering::Equal,
+            (HostMatch::Exact(_), HostMatch::Wildcard(_)) => Ordering::Less,
+            (HostMatch::Wildcard(_), HostMatch::Exact(_)) => Ordering::Greater,
+            (HostMatch::Wildcard(a), HostMatch::Wildcard(b)) => a.len().cmp(&b.len()).reverse()
+        }
+    }
+}
+impl PartialOrd for HostMatch {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Remove the port from a host like "localhost:8080" or "[::1]:8080".
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host
+    }
+}
+
 /// Parse a path into pieces containing either raw strings or patterns to match on:
-fn parse_path(path: &str) -> Vec<PathPiece> {
+fn parse_path(path: &str) -> Vec<PathPiece<'_>> {
     lazy_static!{
         // Are we matching on parts of the path? (.*?) is a non greedy match, to match as little
         // as possible, which is necessary to support multiple match patterns.
@@ -268,6 +418,7 @@ fn convert_path_pieces_to_regex(path_pieces: Vec<PathPiece>, exact: bool) -> Reg
 
 /// Present matches back, given a path to match on.
 pub struct Matches<'a> {
+    regex: &'a Regex,
     captures: regex::Captures<'a>,
     path_tail: &'a str,
     query: &'a str
@@ -277,6 +428,12 @@ impl Matches<'_> {
     pub fn get(&self, name: &str) -> Option<&str> {
         self.captures.name(name).map(|m| m.as_str())
     }
+    /// Iterate over the name and value of each variable that was matched.
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.regex.capture_names()
+            .flatten()
+            .filter_map(move |name| self.get(name).map(|value| (name, value)))
+    }
     pub fn path_tail(&self) -> &str {
         self.path_tail
     }
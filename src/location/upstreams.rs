@@ -0,0 +1,348 @@
+use std::fmt;
+use std::str::FromStr;
+use std::future::Future;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+use futures_util::future::join_all;
+use rand::Rng;
+use colored::*;
+use log::{ info, warn };
+use crate::errors::{ Error };
+
+/// How long to wait before trying an unhealthy upstream again,
+/// if it isn't being actively probed.
+const RETRY_UNHEALTHY_AFTER: Duration = Duration::from_secs(10);
+
+/// How requests (or connections) are spread across several upstreams.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    Random,
+    LeastConnections
+}
+
+impl FromStr for Balance {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Balance, Error> {
+        match s.trim() {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "random" => Ok(Balance::Random),
+            "least-connections" => Ok(Balance::LeastConnections),
+            _ => Err(err!("'{}' is not a valid balancing strategy; expected one of \
+                           'round-robin', 'random' or 'least-connections'", s))
+        }
+    }
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Balance::RoundRobin => "round-robin".fmt(f),
+            Balance::Random => "random".fmt(f),
+            Balance::LeastConnections => "least-connections".fmt(f)
+        }
+    }
+}
+
+/// One or more upstreams that a destination can be served by. Clones (and
+/// anything derived using `map`) share the state needed to balance across them.
+#[derive(Debug,Clone)]
+pub struct Upstreams<T> {
+    targets: Vec<T>,
+    state: Arc<State>
+}
+
+#[derive(Debug)]
+struct State {
+    /// How each upstream is described in logs.
+    names: Vec<String>,
+    /// The next upstream to use, for round-robin balancing.
+    next: AtomicUsize,
+    /// How many requests or connections each upstream is currently handling.
+    active: Vec<AtomicUsize>,
+    /// Whether each upstream is healthy.
+    health: Vec<Mutex<Health>>,
+    /// Set once the upstreams are being actively probed.
+    probing: AtomicBool
+}
+
+#[derive(Debug)]
+struct Health {
+    healthy: bool,
+    /// Consecutive failures to reach the upstream.
+    failures: usize,
+    /// When an unhealthy upstream can next be tried, if it isn't being probed.
+    retry_at: Option<Instant>
+}
+
+impl State {
+    /// Can this upstream be used without going out of our way to?
+    fn is_available(&self, idx: usize, now: Instant) -> bool {
+        let health = self.health[idx].lock().unwrap();
+        health.healthy || (!self.probing.load(Ordering::Relaxed) && health.retry_at.is_some_and(|t| t <= now))
+    }
+    fn record_success(&self, idx: usize) {
+        let mut health = self.health[idx].lock().unwrap();
+        health.failures = 0;
+        health.retry_at = None;
+        if !health.healthy {
+            health.healthy = true;
+            info!("{}", format!("[health] {} is healthy again", self.names[idx]).green());
+        }
+    }
+    fn record_failure(&self, idx: usize, max_fails: usize, reason: &str) {
+        let mut health = self.health[idx].lock().unwrap();
+        health.failures += 1;
+        if health.healthy && health.failures >= max_fails {
+            health.healthy = false;
+            warn!("{}", format!("[health] {} is unhealthy after {} consecutive failures: {}",
+                                self.names[idx], health.failures, reason).red());
+        }
+        if !health.healthy {
+            health.retry_at = Some(Instant::now() + RETRY_UNHEALTHY_AFTER);
+        }
+    }
+}
+
+impl <T> Upstreams<T> {
+    pub fn new(targets: Vec<T>) -> Upstreams<T> where T: fmt::Display {
+        assert!(!targets.is_empty(), "at least one upstream is required");
+        let state = State {
+            names: targets.iter().map(|t| t.to_string()).collect(),
+            next: AtomicUsize::new(0),
+            active: targets.iter().map(|_| AtomicUsize::new(0)).collect(),
+            health: targets.iter().map(|_| Mutex::new(Health { healthy: true, failures: 0, retry_at: None })).collect(),
+            probing: AtomicBool::new(false)
+        };
+        Upstreams { targets, state: Arc::new(state) }
+    }
+    pub fn targets(&self) -> &[T] {
+        &self.targets
+    }
+    /// Share the balancing and health state of some previous upstreams, if they
+    /// have the same targets, so that it isn't forgotten when routes are reloaded.
+    pub fn keep_state_from(&mut self, previous: &Upstreams<T>) -> bool where T: PartialEq {
+        let same = self.targets == previous.targets;
+        if same {
+            self.state = Arc::clone(&previous.state);
+        }
+        same
+    }
+    /// Transform each of the upstreams, keeping the balancing state shared.
+    pub fn map<U>(&self, f: impl FnMut(&T) -> U) -> Upstreams<U> {
+        Upstreams {
+            targets: self.targets.iter().map(f).collect(),
+            state: Arc::clone(&self.state)
+        }
+    }
+    /// Pick an upstream to use, skipping any that have already been tried. Healthy
+    /// upstreams are preferred, but unhealthy ones are used if nothing else is left.
+    /// `None` is returned once every upstream has been tried. The upstream picked
+    /// counts as active until the guard is dropped.
+    pub fn pick(&self, balance: Balance, tried: &[usize]) -> Option<(&T, ActiveGuard)> {
+        let now = Instant::now();
+        let untried: Vec<usize> = (0..self.targets.len()).filter(|idx| !tried.contains(idx)).collect();
+        let available: Vec<usize> = untried.iter().copied().filter(|&idx| self.state.is_available(idx, now)).collect();
+        let candidates = if available.is_empty() { untried } else { available };
+
+        let len = candidates.len();
+        let idx = match len {
+            0 => return None,
+            1 => candidates[0],
+            _ => match balance {
+                Balance::RoundRobin => {
+                    candidates[self.state.next.fetch_add(1, Ordering::Relaxed) % len]
+                },
+                Balance::Random => {
+                    candidates[rand::thread_rng().gen_range(0, len)]
+                },
+                Balance::LeastConnections => {
+                    // Start from a rotating offset so that ties are spread evenly:
+                    let start = self.state.next.fetch_add(1, Ordering::Relaxed);
+                    (0..len)
+                        .map(|n| candidates[(start + n) % len])
+                        .min_by_key(|&idx| self.state.active[idx].load(Ordering::Relaxed))
+                        .unwrap()
+                }
+            }
+        };
+
+        // Only let one request at a time through to an unhealthy upstream that's due a retry:
+        {
+            let mut health = self.state.health[idx].lock().unwrap();
+            if !health.healthy {
+                health.retry_at = Some(now + RETRY_UNHEALTHY_AFTER);
+            }
+        }
+
+        self.state.active[idx].fetch_add(1, Ordering::Relaxed);
+        Some((&self.targets[idx], ActiveGuard { state: Arc::clone(&self.state), idx }))
+    }
+    /// Periodically probe each upstream using the function provided, recording the
+    /// results, until the upstreams are dropped. Upstreams that are being probed are
+    /// only considered healthy again once a probe succeeds. Calling this again for
+    /// the same upstreams (or a clone of them) does nothing.
+    pub fn spawn_probes<F, Fut>(&self, interval: Duration, max_fails: usize, probe: F)
+    where
+        T: Clone + Send + Sync + 'static,
+        F: Fn(T) -> Fut + Send + 'static,
+        Fut: Future<Output=Result<(), String>> + Send
+    {
+        if self.state.probing.swap(true, Ordering::Relaxed) {
+            return
+        }
+        let targets = self.targets.clone();
+        let state = Arc::downgrade(&self.state);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let results = join_all(targets.iter().cloned().map(&probe)).await;
+                let state = match state.upgrade() {
+                    Some(state) => state,
+                    None => return
+                };
+                for (idx, result) in results.into_iter().enumerate() {
+                    match result {
+                        Ok(()) => state.record_success(idx),
+                        Err(reason) => state.record_failure(idx, max_fails, &reason)
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Balancing state is ignored when comparing upstreams.
+impl <T: PartialEq> PartialEq for Upstreams<T> {
+    fn eq(&self, other: &Upstreams<T>) -> bool {
+        self.targets == other.targets
+    }
+}
+impl <T: Eq> Eq for Upstreams<T> {}
+
+/// Marks an upstream as handling a request or connection until dropped.
+#[derive(Debug)]
+pub struct ActiveGuard {
+    state: Arc<State>,
+    idx: usize
+}
+
+impl ActiveGuard {
+    /// The index of the upstream that was picked.
+    pub fn index(&self) -> usize {
+        self.idx
+    }
+    /// Note that we managed to reach the upstream.
+    pub fn succeeded(&self) {
+        self.state.record_success(self.idx);
+    }
+    /// Note that we couldn't reach the upstream. After `max_fails` consecutive
+    /// failures, it's marked as unhealthy.
+    pub fn failed(&self, max_fails: usize, reason: &str) {
+        self.state.record_failure(self.idx, max_fails, reason);
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.state.active[self.idx].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn round_robin_cycles_through_upstreams() {
+        let upstreams = Upstreams::new(vec![1, 2, 3]);
+        let picked: Vec<_> = (0..6).map(|_| *upstreams.pick(Balance::RoundRobin, &[]).unwrap().0).collect();
+        assert_eq!(picked, vec![1, 2, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn least_connections_avoids_busy_upstreams() {
+        let upstreams = Upstreams::new(vec![1, 2, 3]);
+        let (first, _first_guard) = upstreams.pick(Balance::LeastConnections, &[]).unwrap();
+        let (second, _second_guard) = upstreams.pick(Balance::LeastConnections, &[]).unwrap();
+        let (third, third_guard) = upstreams.pick(Balance::LeastConnections, &[]).unwrap();
+        let mut picked = vec![*first, *second, *third];
+        picked.sort();
+        assert_eq!(picked, vec![1, 2, 3]);
+
+        // Once the third upstream is free again, it's the only sensible choice:
+        let third = *third;
+        drop(third_guard);
+        for _ in 0..3 {
+            assert_eq!(*upstreams.pick(Balance::LeastConnections, &[]).unwrap().0, third);
+        }
+    }
+
+    #[test]
+    fn mapped_upstreams_share_state() {
+        let upstreams = Upstreams::new(vec![1, 2]);
+        let mapped = upstreams.map(|n| n * 10);
+        assert_eq!(*upstreams.pick(Balance::RoundRobin, &[]).unwrap().0, 1);
+        assert_eq!(*mapped.pick(Balance::RoundRobin, &[]).unwrap().0, 20);
+    }
+
+    #[test]
+    fn tried_upstreams_are_skipped() {
+        let upstreams = Upstreams::new(vec![1, 2, 3]);
+        let (first, first_guard) = upstreams.pick(Balance::Random, &[]).unwrap();
+        let (second, second_guard) = upstreams.pick(Balance::Random, &[first_guard.index()]).unwrap();
+        let (third, third_guard) = upstreams.pick(Balance::Random, &[first_guard.index(), second_guard.index()]).unwrap();
+        let mut picked = vec![*first, *second, *third];
+        picked.sort();
+        assert_eq!(picked, vec![1, 2, 3]);
+        assert!(upstreams.pick(Balance::Random, &[0, 1, 2]).is_none());
+        drop(third_guard);
+    }
+
+    #[test]
+    fn unhealthy_upstreams_are_avoided() {
+        let upstreams = Upstreams::new(vec![1, 2]);
+        let (_, guard) = upstreams.pick(Balance::RoundRobin, &[]).unwrap();
+        assert_eq!(guard.index(), 0);
+        guard.failed(2, "oops");
+        guard.failed(2, "oops");
+        for _ in 0..3 {
+            assert_eq!(*upstreams.pick(Balance::RoundRobin, &[]).unwrap().0, 2);
+        }
+        // Unhealthy upstreams are still used if there's nothing else to try:
+        assert_eq!(*upstreams.pick(Balance::RoundRobin, &[1]).unwrap().0, 1);
+        // And are used as normal once they recover:
+        guard.succeeded();
+        let picked: Vec<_> = (0..2).map(|_| *upstreams.pick(Balance::RoundRobin, &[]).unwrap().0).collect();
+        assert!(picked.contains(&1) && picked.contains(&2));
+    }
+
+    #[test]
+    fn state_is_kept_for_the_same_targets() {
+        let previous = Upstreams::new(vec![1, 2]);
+        previous.pick(Balance::RoundRobin, &[]).unwrap().1.failed(1, "oops");
+
+        let mut changed = Upstreams::new(vec![1, 3]);
+        assert!(!changed.keep_state_from(&previous));
+        assert_eq!(*changed.pick(Balance::RoundRobin, &[]).unwrap().0, 1);
+
+        let mut same = Upstreams::new(vec![1, 2]);
+        assert!(same.keep_state_from(&previous));
+        for _ in 0..3 {
+            assert_eq!(*same.pick(Balance::RoundRobin, &[]).unwrap().0, 2);
+        }
+    }
+
+    #[test]
+    fn balance_can_be_parsed() {
+        assert_eq!("round-robin".parse::<Balance>().unwrap(), Balance::RoundRobin);
+        assert_eq!("random".parse::<Balance>().unwrap(), Balance::Random);
+        assert_eq!("least-connections".parse::<Balance>().unwrap(), Balance::LeastConnections);
+        assert!("fastest".parse::<Balance>().is_err());
+    }
+
+}
@@ -55,7 +55,7 @@ impl SplitUrl<'_> {
         let host = Host::parse(if host.is_empty() { "localhost" } else { host })?;
 
         // Split remaining input into path and query parts:
-        let (raw_path, query) = split_path_and_query(&input);
+        let (raw_path, query) = split_path_and_query(input);
 
         // Normalise path if needed by adding prefix /:
         let path = if input.starts_with("/") {
@@ -2,30 +2,63 @@
 
 #[macro_use] mod errors;
 mod examples;
+mod config;
+mod client;
+mod health;
+mod headers;
+mod cors;
+mod template;
+mod recording;
+mod har;
+mod files;
+mod proxy;
 mod routes;
 mod location;
 mod matcher;
+mod servers;
+mod tls;
 mod logging;
+mod util;
+#[cfg(test)]
+mod test_support;
 
 use std::env;
-use std::collections::HashMap;
+use std::convert::Infallible;
 use std::net::{ SocketAddr };
+use std::path::{ Path, PathBuf };
 use std::sync::Arc;
-use clap::{ App, AppSettings, crate_version };
-use hyper::{ Client, Body, Request, Response, Server };
-use hyper::service::{ service_fn, make_service_fn };
-use hyper_tls::HttpsConnector;
-use tokio::{ self, fs, net::{ TcpListener, TcpStream } };
+use std::time::Duration;
+use clap::{ App, AppSettings, Arg, crate_version };
+use hyper::{ Body, Request, Response, StatusCode, body::{ Bytes, HttpBody } };
+use hyper::server::conn::Http;
+use hyper::service::{ service_fn };
+use tokio::{ self, fs, time, sync::watch, net::{ TcpListener, TcpStream } };
 use colored::*;
-use futures_util::{ future::join_all, join };
-
-use routes::{ Route };
-use location::{ ResolvedLocation, Protocol };
-use matcher::Matcher;
+use futures_util::{ join, StreamExt, future::{ select, Either } };
+
+use routes::{ Route, HostHeader };
+use location::{ ResolvedLocation, Balance, ActiveGuard };
+use matcher::{ Matcher, Resolved };
+use servers::{ Servers, HttpState };
+use client::{ HttpClient, ClientOptions };
+use proxy::{ ConnectionInfo };
+use headers::{ HeaderRule };
+use cors::{ Cors };
+use recording::{ Recorder, Mode };
+use har::{ Har };
+use health::{ HealthCheck };
+use tls::{ Connection, TlsFiles };
 use errors::{ Error };
 
 use log::{ debug, info, warn, error };
 
+/// How often to check the config file for changes.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The largest request body we'll hold in memory in order to use it more than once.
+/// Larger bodies are streamed to the first destination that needs them instead.
+const MAX_BUFFERED_BODY_SIZE: usize = 10 * 1024 * 1024;
+
 
 /// Our application entry point:
 #[tokio::main]
@@ -41,88 +74,370 @@ async fn main() {
 async fn run() -> Result<(), Error> {
 
     let route_args: Vec<String> = env::args().skip(1).collect();
-    let (routes, other_args) = routes::from_args(&route_args).map_err(|e| {
+    let (mut routes, other_args) = routes::from_args(&route_args).map_err(|e| {
         err!("failed to parse routes: {}", e)
     })?;
 
-    let _ = App::new("weave")
+    let matches = App::new("weave")
         .author("James Wilson <james@jsdw.me>")
         .about("A lightweight HTTP/TCP router and file server.")
         .version(crate_version!())
         .after_help(&*examples::text())
         .usage("weave SOURCE to DEST [and SOURCE to DEST ...] [OPTIONS]")
         .setting(AppSettings::NoBinaryName)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .short("c")
+            .value_name("FILE")
+            .help("Load routes from a TOML config file, in addition to any provided as arguments")
+            .takes_value(true))
+        .arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .value_name("FILE")
+            .help("A PEM encoded certificate chain to use for HTTPS routes (a self-signed certificate is generated otherwise)")
+            .requires("tls-key")
+            .takes_value(true))
+        .arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .value_name("FILE")
+            .help("A PEM encoded private key to use for HTTPS routes")
+            .requires("tls-cert")
+            .takes_value(true))
+        .arg(Arg::with_name("directory-listing")
+            .long("directory-listing")
+            .help("List the contents of directories that don't contain an index file, for routes given as arguments"))
+        .arg(Arg::with_name("fallback-on")
+            .long("fallback-on")
+            .value_name("CODES")
+            .help("Comma separated status codes that cause fallback destinations to be tried, for routes given as arguments [default: 404]")
+            .validator(|s| routes::parse_status_codes(&s).map(|_| ()).map_err(|e| e.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("balance")
+            .long("balance")
+            .value_name("STRATEGY")
+            .help("How to spread requests across destinations that list several upstreams, for routes given as arguments \
+                   (round-robin, random or least-connections) [default: round-robin]")
+            .validator(|s| s.parse::<Balance>().map(|_| ()).map_err(|e| e.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("max-fails")
+            .long("max-fails")
+            .value_name("NUMBER")
+            .help("Consecutive failures to reach a destination before it's considered unhealthy and avoided, for routes given as arguments [default: 3]")
+            .validator(|s| s.parse::<usize>().ok().filter(|&n| n > 0).map(|_| ()).ok_or_else(|| "must be a number greater than 0".to_owned()))
+            .takes_value(true))
+        .arg(Arg::with_name("health-check-interval")
+            .long("health-check-interval")
+            .value_name("SECONDS")
+            .help("Probe destinations this often to check that they're healthy, for routes given as arguments (they aren't probed by default)")
+            .validator(|s| s.parse::<u64>().ok().filter(|&n| n > 0).map(|_| ()).ok_or_else(|| "must be a number of seconds greater than 0".to_owned()))
+            .takes_value(true))
+        .arg(Arg::with_name("health-check-path")
+            .long("health-check-path")
+            .value_name("PATH")
+            .help("The path to request when probing HTTP destinations [default: /]")
+            .requires("health-check-interval")
+            .takes_value(true))
+        .arg(Arg::with_name("host-header")
+            .long("host-header")
+            .value_name("HOST")
+            .help("The Host header to send to HTTP destinations, for routes given as arguments; 'upstream' for the destination's \
+                   host, 'preserve' for the one in the request, or any other value to use that [default: upstream]")
+            .validator(|s| s.parse::<HostHeader>().map(|_| ()).map_err(|e| e.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("trust-forwarded-headers")
+            .long("trust-forwarded-headers")
+            .help("Add to the forwarding headers (eg X-Forwarded-For) that requests arrive with rather than replacing them, \
+                   for routes given as arguments. Use this if weave is behind another proxy"))
+        .arg(Arg::with_name("request-header")
+            .long("request-header")
+            .value_name("RULE")
+            .help("Change the headers of requests that are proxied, for routes given as arguments. Rules look like \
+                   'set Name: value', 'append Name: value', 'remove Name' or 'rename Name: NewName', and can be given more than once")
+            .validator(|s| s.parse::<HeaderRule>().map(|_| ()).map_err(|e| e.to_string()))
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("response-header")
+            .long("response-header")
+            .value_name("RULE")
+            .help("Change the headers of responses, for routes given as arguments (rules are as for --request-header)")
+            .validator(|s| s.parse::<HeaderRule>().map(|_| ()).map_err(|e| e.to_string()))
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("cors")
+            .long("cors")
+            .help("Answer CORS preflight requests and add CORS headers to responses, for routes given as arguments. \
+                   Any origin, method and headers are allowed unless the options below are given"))
+        .arg(Arg::with_name("cors-origins")
+            .long("cors-origins")
+            .value_name("ORIGINS")
+            .help("A comma separated list of origins allowed to make requests, eg 'http://localhost:3000' (implies --cors)")
+            .takes_value(true))
+        .arg(Arg::with_name("cors-methods")
+            .long("cors-methods")
+            .value_name("METHODS")
+            .help("A comma separated list of methods that cross-origin requests can use (implies --cors)")
+            .takes_value(true))
+        .arg(Arg::with_name("cors-headers")
+            .long("cors-headers")
+            .value_name("HEADERS")
+            .help("A comma separated list of headers that cross-origin requests can send (implies --cors)")
+            .takes_value(true))
+        .arg(Arg::with_name("cors-credentials")
+            .long("cors-credentials")
+            .help("Allow cross-origin requests to send cookies and other credentials (implies --cors)"))
+        .arg(Arg::with_name("cors-max-age")
+            .long("cors-max-age")
+            .value_name("SECONDS")
+            .help("How long browsers can cache the response to a preflight request for (implies --cors)")
+            .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|_| "must be a number of seconds".to_owned()))
+            .takes_value(true))
+        .arg(Arg::with_name("record")
+            .long("record")
+            .value_name("DIR")
+            .help("Save each request proxied to an HTTP destination, and the response to it, to a file in this directory")
+            .conflicts_with("replay")
+            .takes_value(true))
+        .arg(Arg::with_name("replay")
+            .long("replay")
+            .value_name("DIR")
+            .help("Respond to requests for HTTP destinations with the recordings in this directory rather than proxying them")
+            .takes_value(true))
+        .arg(Arg::with_name("replay-match")
+            .long("replay-match")
+            .value_name("FIELDS")
+            .help("A comma separated list of what requests must have in common with a recording to be served it, \
+                   out of 'method', 'path', 'query' and 'body' [default: method,path,query]")
+            .validator(|s| recording::parse_match_on(&s).map(|_| ()).map_err(|e| e.to_string()))
+            .requires("replay")
+            .takes_value(true))
+        .arg(Arg::with_name("har")
+            .long("har")
+            .value_name("FILE")
+            .help("Write every HTTP request and response (and how long it took) to a HAR file")
+            .takes_value(true))
+        .arg(Arg::with_name("har-body-limit")
+            .long("har-body-limit")
+            .value_name("BYTES")
+            .help("Include request and response bodies in the HAR file, up to this many bytes each")
+            .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+            .requires("har")
+            .takes_value(true))
+        .arg(Arg::with_name("no-keep-alive")
+            .long("no-keep-alive")
+            .help("Open a new connection for every request that's proxied, rather than reusing idle ones"))
+        .arg(Arg::with_name("pool-idle-timeout")
+            .long("pool-idle-timeout")
+            .value_name("SECONDS")
+            .help("How long idle connections to destinations are kept open for (0 to keep them open indefinitely) [default: 90]")
+            .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|_| "must be a number of seconds".to_owned()))
+            .takes_value(true))
+        .arg(Arg::with_name("pool-max-idle-per-host")
+            .long("pool-max-idle-per-host")
+            .value_name("NUMBER")
+            .help("The maximum number of idle connections to keep open to each destination host [default: no limit]")
+            .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|_| "must be a number".to_owned()))
+            .takes_value(true))
         .get_matches_from(other_args);
 
+    if matches.is_present("directory-listing") {
+        for route in &mut routes {
+            route.options.directory_listing = true;
+        }
+    }
+    if let Some(codes) = matches.value_of("fallback-on") {
+        let codes = routes::parse_status_codes(codes)?;
+        for route in &mut routes {
+            route.options.fallback_on = codes.clone();
+        }
+    }
+    if let Some(balance) = matches.value_of("balance") {
+        let balance: Balance = balance.parse()?;
+        for route in &mut routes {
+            route.options.balance = balance;
+        }
+    }
+    if let Some(max_fails) = matches.value_of("max-fails") {
+        let max_fails: usize = max_fails.parse().unwrap();
+        for route in &mut routes {
+            route.options.max_fails = max_fails;
+        }
+    }
+    if let Some(host_header) = matches.value_of("host-header") {
+        let host_header: HostHeader = host_header.parse()?;
+        for route in &mut routes {
+            route.options.host_header = host_header.clone();
+        }
+    }
+    if let Some(rules) = matches.values_of("request-header") {
+        let rules: Vec<HeaderRule> = rules.map(|r| r.parse()).collect::<Result<_,_>>()?;
+        for route in &mut routes {
+            route.options.request_headers = rules.clone();
+        }
+    }
+    if let Some(rules) = matches.values_of("response-header") {
+        let rules: Vec<HeaderRule> = rules.map(|r| r.parse()).collect::<Result<_,_>>()?;
+        for route in &mut routes {
+            route.options.response_headers = rules.clone();
+        }
+    }
+    let cors_args = ["cors", "cors-origins", "cors-methods", "cors-headers", "cors-credentials", "cors-max-age"];
+    if cors_args.iter().any(|arg| matches.is_present(arg)) {
+        let list = |arg| matches.value_of(arg).map(|s| s.split(',').collect::<Vec<_>>()).unwrap_or_default();
+        let cors = Cors::new(
+            &list("cors-origins"),
+            &list("cors-methods"),
+            &list("cors-headers"),
+            matches.is_present("cors-credentials"),
+            matches.value_of("cors-max-age").map(|secs| secs.parse().unwrap())
+        )?;
+        for route in &mut routes {
+            route.options.cors = Some(cors.clone());
+        }
+    }
+    if matches.is_present("trust-forwarded-headers") {
+        for route in &mut routes {
+            route.options.trust_forwarded_headers = true;
+        }
+    }
+    if let Some(secs) = matches.value_of("health-check-interval") {
+        let check = HealthCheck {
+            interval: Duration::from_secs(secs.parse().unwrap()),
+            path: routes::health_check_path(matches.value_of("health-check-path").unwrap_or("/"))
+        };
+        for route in &mut routes {
+            route.options.health_check = Some(check.clone());
+        }
+    }
+
+    // Append any routes that are declared in a config file:
+    let config_path = matches.value_of("config").map(PathBuf::from);
+    let arg_routes = routes.clone();
+    if let Some(path) = &config_path {
+        let config_routes = config::from_file(path).map_err(|e| {
+            err!("failed to parse routes: {}", e)
+        })?;
+        routes.extend(config_routes);
+    }
+
     if routes.is_empty() {
         return Err(err!("No routes have been provided. Use -h or --help for more information"));
     }
 
-    // Log our routes:
-    for route in &routes {
-        info!("Routing {} to {}", route.src, route.dest);
-    }
+    log_routes(&routes);
 
-    // Partition provided routes based on the SocketAddr we'll serve them on:
-    let mut route_map = HashMap::new();
-    for route in routes {
-        let socket_addr = route.src_socket_addr()?;
-        let rs: &mut Vec<Route> = route_map.entry(socket_addr).or_default();
-        rs.push(route);
+    // Start servers to handle requests on each socket address:
+    let default_tls = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(cert), Some(key)) => Some(TlsFiles { cert: cert.into(), key: key.into() }),
+        _ => None
+    };
+    let mut client_opts = ClientOptions::default();
+    if matches.is_present("no-keep-alive") {
+        client_opts.keep_alive = false;
+    }
+    if let Some(secs) = matches.value_of("pool-idle-timeout") {
+        let secs: u64 = secs.parse().unwrap();
+        client_opts.idle_timeout = if secs == 0 { None } else { Some(Duration::from_secs(secs)) };
     }
+    if let Some(max) = matches.value_of("pool-max-idle-per-host") {
+        client_opts.max_idle_per_host = max.parse().unwrap();
+    }
+    let recorder = if let Some(dir) = matches.value_of("record") {
+        info!("Recording requests to HTTP destinations in {}", dir);
+        Some(Recorder::record(dir)?)
+    } else if let Some(dir) = matches.value_of("replay") {
+        let match_on = recording::parse_match_on(matches.value_of("replay-match").unwrap_or("method,path,query"))?;
+        let recorder = Recorder::replay(dir, match_on)?;
+        info!("Replaying {} recordings from {} rather than proxying requests", recorder.recording_count(), dir);
+        Some(recorder)
+    } else {
+        None
+    };
+    let har = match matches.value_of("har") {
+        Some(path) => {
+            let body_limit = matches.value_of("har-body-limit").map(|s| s.parse().unwrap()).unwrap_or(0);
+            info!("Writing HTTP requests and responses to {}", path);
+            Some(Har::create(path, body_limit).await?)
+        },
+        None => None
+    };
+    let mut servers = Servers::new(default_tls, client::new(&client_opts), recorder, har);
+    servers.update(routes).await?;
+
+    // If routes were loaded from a config file, keep an eye on it and
+    // update our servers whenever it changes. Otherwise, wait for the
+    // servers to finish (shouldn't happen unless they all fail):
+    match config_path {
+        Some(path) => watch_config(&path, arg_routes, servers).await,
+        None => servers.closed().await
+    }
+    Ok(())
+}
 
-    // Map each addr+route pair into a future that will handle requests:
-    let servers = route_map.into_iter().map(|(socket_addr, routes)| async move {
-        let mut http_routes = Vec::new();
-        let mut tcp_route = None;
+/// Poll the config file for changes, updating the routes that are being served
+/// whenever it is modified. Invalid changes are logged and otherwise ignored.
+async fn watch_config(path: &Path, arg_routes: Vec<Route>, mut servers: Servers) {
+    let modified = |path| async move {
+        fs::metadata(path).await.and_then(|m| m.modified()).ok()
+    };
 
-        for route in routes {
-            let protocol = route.protocol();
-            match protocol {
-                Protocol::Http => {
-                    http_routes.push(route);
-                },
-                Protocol::Tcp => {
-                    tcp_route = Some(route);
-                }
-                Protocol::Https | Protocol::HttpStatusCode => {
-                    panic!("These are not valid source protocols, so we shouldn't get here");
-                }
-            }
-        }
+    let mut last_modified = modified(path).await;
+    let mut interval = time::interval(CONFIG_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
 
-        let tcp_fut = async move {
-            if let Some(r) = tcp_route {
-                handle_tcp_requests(socket_addr, r).await;
-            }
-        };
-        let http_fut = async move {
-            if http_routes.len() > 0 {
-                handle_http_requests(socket_addr, http_routes).await;
-            }
+        let current_modified = modified(path).await;
+        if current_modified.is_none() || current_modified == last_modified {
+            continue
+        }
+        last_modified = current_modified;
+
+        info!("Reloading routes from {}", path.display());
+        let reloaded = match load_config_routes(path, &arg_routes) {
+            Ok(routes) => {
+                log_routes(&routes);
+                servers.update(routes).await
+            },
+            Err(e) => Err(e)
         };
+        if let Err(e) = reloaded {
+            error!("failed to reload routes (keeping existing routes): {}", e);
+        }
+    }
+}
 
-        join!(tcp_fut, http_fut)
-    });
-
-    // Wait for these to finish (shouldn't happen unless they all fail):
-    join_all(servers).await;
-    Ok(())
+/// Load routes from a config file, appending them to those given as arguments.
+fn load_config_routes(path: &Path, arg_routes: &[Route]) -> Result<Vec<Route>, Error> {
+    let mut routes = arg_routes.to_vec();
+    routes.extend(config::from_file(path)?);
+    if routes.is_empty() {
+        return Err(err!("No routes have been provided"));
+    }
+    Ok(routes)
 }
 
-/// Handle raw TCP proxying
-async fn handle_tcp_requests(socket_addr: SocketAddr, route: Route) {
-    if let Err(e) = do_handle_tcp_requests(socket_addr, route).await {
-        error!("{}", e);
+/// Log the routes that we're going to serve.
+fn log_routes(routes: &[Route]) {
+    for route in routes {
+        info!("Routing {}", route);
     }
 }
-async fn do_handle_tcp_requests(socket_addr: SocketAddr, route: Route) -> Result<(),Error> {
-    let dest_socket_addr = route.dest_socket_addr().unwrap();
-    let mut listener = TcpListener::bind(socket_addr).await?;
+
+/// Handle raw TCP proxying
+async fn handle_tcp_requests(mut listener: TcpListener, socket_addr: SocketAddr, route: watch::Receiver<Route>) {
+    let shutdown = servers::shutdown(route.clone(), socket_addr);
+    futures_util::pin_mut!(shutdown);
 
     loop {
-        // Accept an incoming connection:
-        let (mut src_socket, _) = match listener.accept().await {
+        // Accept an incoming connection, unless we've been told to stop:
+        let accept = listener.accept();
+        futures_util::pin_mut!(accept);
+        let accepted = match select(accept, &mut shutdown).await {
+            Either::Left((accepted, _)) => accepted,
+            Either::Right(_) => return
+        };
+        let (mut src_socket, _) = match accepted {
             Ok(sock) => sock,
             Err(e) => {
                 warn!("{}", format!("[tcp] error accepting connection on {}: {}",
@@ -130,16 +445,34 @@ async fn do_handle_tcp_requests(socket_addr: SocketAddr, route: Route) -> Result
                 continue
             }
         };
+        // Use the latest destinations we've been given:
+        let (dest_socket_addrs, balance, max_fails) = {
+            let route = route.borrow();
+            (route.dest_socket_addrs().unwrap().clone(), route.options.balance, route.options.max_fails)
+        };
         // Proxy data to the outbound route provided:
         tokio::spawn(async move {
             let (mut src_read, mut src_write) = src_socket.split();
 
-            let mut dest_socket = match TcpStream::connect(dest_socket_addr).await {
-                Ok(sock) => sock,
-                Err(e) => {
-                    warn!("{}", format!("[tcp] error connecting to destination {}: {}",
-                                        dest_socket_addr, e).red());
-                    return
+            // Connect to one of the destinations, trying the others if we can't. The
+            // destination is in use for as long as the connection is open:
+            let mut tried = vec![];
+            let (mut dest_socket, dest_socket_addr, _active_guard) = loop {
+                let (dest_socket_addr, active_guard) = match dest_socket_addrs.pick(balance, &tried) {
+                    Some(picked) => picked,
+                    None => return
+                };
+                tried.push(active_guard.index());
+                match TcpStream::connect(dest_socket_addr).await {
+                    Ok(sock) => {
+                        active_guard.succeeded();
+                        break (sock, *dest_socket_addr, active_guard)
+                    },
+                    Err(e) => {
+                        warn!("{}", format!("[tcp] error connecting to destination {}: {}",
+                                            dest_socket_addr, e).red());
+                        active_guard.failed(max_fails, &e.to_string());
+                    }
                 }
             };
             let (mut dest_read, mut dest_write) = dest_socket.split();
@@ -163,38 +496,79 @@ async fn do_handle_tcp_requests(socket_addr: SocketAddr, route: Route) -> Result
 }
 
 /// Handle incoming HTTP requests by matching on routes and dispatching as necessary
-async fn handle_http_requests(socket_addr: SocketAddr, routes: Vec<Route>) {
-
-    let matcher = Arc::new(Matcher::new(routes));
-    let make_service = make_service_fn(move |_| {
-        let matcher = Arc::clone(&matcher);
-        let svc = Ok::<_,Error>(service_fn(move |req| {
-            let matcher = Arc::clone(&matcher);
-            async move {
-                let res = handle_http_request(req, &socket_addr, &matcher).await;
-                // We don't return any errors, so need to tell Rust
-                // what the error type would be:
-                Result::<_,Error>::Ok(res)
+async fn handle_http_requests(mut listener: TcpListener, socket_addr: SocketAddr, state: watch::Receiver<HttpState>) {
+    let shutdown = servers::shutdown(state.clone(), socket_addr);
+    futures_util::pin_mut!(shutdown);
+
+    loop {
+        // Accept an incoming connection, unless we've been told to stop. Connections
+        // that have already been accepted are left to finish what they're doing:
+        let accept = listener.accept();
+        futures_util::pin_mut!(accept);
+        let accepted = match select(accept, &mut shutdown).await {
+            Either::Left((accepted, _)) => accepted,
+            Either::Right(_) => return
+        };
+        let (socket, peer_addr) = match accepted {
+            Ok(sock) => sock,
+            Err(e) => {
+                warn!("{}", format!("[http] error accepting connection on {}: {}",
+                                    socket_addr, e).red());
+                continue
             }
-        }));
+        };
 
-        // Return a Future:
-        async { svc }
-    });
+        let state = state.clone();
+        tokio::spawn(async move {
+            // Complete the TLS handshake first if we need to:
+            let tls = state.borrow().tls.clone();
+            let conn_info = ConnectionInfo { peer_addr, port: socket_addr.port(), secure: tls.is_some() };
+            let conn = match tls {
+                None => Connection::Plain(socket),
+                Some(acceptor) => match acceptor.accept(socket).await {
+                    Ok(stream) => Connection::Tls(Box::new(stream)),
+                    Err(e) => {
+                        debug!("[https] error completing TLS handshake on {}: {}", socket_addr, e);
+                        return
+                    }
+                }
+            };
 
-    let server = Server::bind(&socket_addr).serve(make_service);
-    if let Err(e) = server.await {
-        error!("{}", e);
+            let svc = service_fn(move |req| {
+                // Use the latest routes we've been given:
+                let (matcher, client, recorder, har) = {
+                    let state = state.borrow();
+                    (Arc::clone(&state.matcher), state.client.clone(), state.recorder.clone(), state.har.clone())
+                };
+                async move {
+                    let res = handle_http_request(req, &socket_addr, &conn_info, &matcher, &client, recorder.as_deref(), har.as_ref()).await;
+                    // We don't return any errors, so need to tell Rust
+                    // what the error type would be:
+                    Result::<_,Infallible>::Ok(res)
+                }
+            });
+
+            if let Err(e) = Http::new().serve_connection(conn, svc).with_upgrades().await {
+                debug!("error serving connection on {}: {}", socket_addr, e);
+            }
+        });
     }
 }
 
 /// Handle a single request, given a matcher that defines how to map from input to output:
-async fn handle_http_request(req: Request<Body>, socket_addr: &SocketAddr, matcher: &Matcher) -> Response<Body> {
+async fn handle_http_request(req: Request<Body>, socket_addr: &SocketAddr, conn_info: &ConnectionInfo, matcher: &Matcher, client: &HttpClient, recorder: Option<&Recorder>, har: Option<&Arc<Har>>) -> Response<Body> {
     let before_time = std::time::Instant::now();
+    let (req, exchange) = match har {
+        Some(har) => {
+            let (req, exchange) = har.start(req, conn_info, before_time);
+            (req, Some(exchange))
+        },
+        None => (req, None)
+    };
     let src_path = format!("{}{}", socket_addr, req.uri());
-    let dest_path = matcher.resolve(req.uri());
+    let resolved = matcher.resolve(&req);
 
-    match dest_path {
+    let resp = match resolved {
         None => {
             let duration = before_time.elapsed();
             let not_found_string = format!("[no matching routes] {} in {:#?}", src_path, duration);
@@ -204,8 +578,22 @@ async fn handle_http_request(req: Request<Body>, socket_addr: &SocketAddr, match
                 .body(Body::from("Weave: No routes matched"))
                 .unwrap()
         },
-        Some(dest_path) => {
-            match do_handle_http_request(req, &dest_path).await {
+        Some(resolved) => {
+            let origin = req.headers().get(hyper::header::ORIGIN).cloned();
+            let is_preflight = cors::preflight_method(req.method(), req.headers()).is_some();
+            let (served_by, served_desc, result) = do_handle_http_request(req, &resolved, conn_info, client, recorder).await;
+
+            // Describe the location that served the request, noting any that we fell back from:
+            let locations: Vec<_> = std::iter::once(&resolved.location).chain(&resolved.fallbacks).collect();
+            let served_location = locations[served_by];
+            let dest_path = if served_by == 0 {
+                served_desc
+            } else {
+                let skipped: Vec<_> = locations[..served_by].iter().map(|l| l.to_string()).collect();
+                format!("{} (falling back from {})", served_desc, skipped.join(", "))
+            };
+
+            let mut resp = match result {
                 Ok(resp) => {
                     let duration = before_time.elapsed();
                     let status_code = resp.status().as_u16();
@@ -213,13 +601,13 @@ async fn handle_http_request(req: Request<Body>, socket_addr: &SocketAddr, match
                     let info_string = format!("[{}] {} to {} in {:#?}",
                         resp.status().as_str(),
                         src_path,
-                        dest_path.to_string(),
+                        dest_path,
                         duration);
 
                     let info_string_colored =
-                        if let ResolvedLocation::HttpStatusCode{..} = dest_path { info_string.green() }
-                        else if status_code >= 200 && status_code < 300 { info_string.green() }
-                        else if status_code >= 300 && status_code < 400 { info_string.yellow() }
+                        if let ResolvedLocation::HttpStatusCode{..} | ResolvedLocation::Redirect{..} | ResolvedLocation::Inline{..} = served_location { info_string.green() }
+                        else if (100..300).contains(&status_code) { info_string.green() }
+                        else if (300..400).contains(&status_code) { info_string.yellow() }
                         else { info_string.red() };
 
                     info!("{}", info_string_colored);
@@ -227,79 +615,249 @@ async fn handle_http_request(req: Request<Body>, socket_addr: &SocketAddr, match
                 },
                 Err(err) => {
                     let duration = before_time.elapsed();
-                    let error_string = format!("[500] {} to {} ({}) in {:#?}",
+                    let error_string = format!("[502] {} to {} ({}) in {:#?}",
                         src_path,
-                        dest_path.to_string(),
+                        dest_path,
                         err,
                         duration);
                     warn!("{}", error_string.red());
                     Response::builder()
-                        .status(500)
+                        .status(502)
                         .body(Body::from(format!("Weave: {}", err)))
                         .unwrap()
                 }
+            };
+
+            // Header rules apply to every response for the route, errors included:
+            headers::apply(&resolved.response_headers, resp.headers_mut());
+
+            // Let browsers see the response to cross-origin requests, errors included:
+            if let Some(cors) = &resolved.options.cors {
+                if !is_preflight {
+                    cors.decorate(origin.as_ref(), resp.headers_mut());
+                }
             }
+            resp
         }
-    }
+    };
 
+    match exchange {
+        Some(exchange) => exchange.finish(resp),
+        None => resp
+    }
 }
 
-async fn do_handle_http_request(mut req: Request<Body>, dest_path: &ResolvedLocation) -> Result<Response<Body>, Error> {
-    match dest_path {
-        // Return a status code:
-        ResolvedLocation::HttpStatusCode(code) => {
-            let res = Response::builder()
-                .status(*code)
-                .body(Body::empty())
-                .unwrap();
-            Ok(res)
-        },
-        // Proxy to the URI our request matched against:
-        ResolvedLocation::Url(url) => {
-            // Set the request URI to our new destination:
-            *req.uri_mut() = format!("{}", url).parse().unwrap();
-            // Remove the host header (it's set according to URI if not present):
-            req.headers_mut().remove("host");
-            // Support HTTPS:
-            let https = HttpsConnector::new();
-            // Proxy the request through and pass back the response:
-            let response = Client::builder()
-                .build(https)
-                .request(req)
-                .await?;
-            Ok(response)
-        },
-        // Proxy to the filesystem:
-        ResolvedLocation::FilePath(path) => {
-
-            let mut file = Err(err!("File not found"));
-            let mut mime = None;
-
-            for end in &["", "index.htm", "index.html"] {
-                let mut p = path.clone();
-                if !end.is_empty() { p.push(end) }
-                mime = Some(mime_guess::from_path(&p).first_or_octet_stream());
-                file = fs::read(p).await.map_err(|e| err!("{}", e));
-                if file.is_ok() { break }
-            }
+/// Serve a request using the resolved location, or its fallbacks if the location
+/// responds with a status that the route falls back on (or can't be reached at all).
+/// Alongside the result, we hand back the index of the location that served it
+/// (0 for the main location, 1 for the first fallback and so on), and a description
+/// of it (which names the upstream used if the location has several).
+async fn do_handle_http_request(req: Request<Body>, resolved: &Resolved<'_>, conn_info: &ConnectionInfo, client: &HttpClient, recorder: Option<&Recorder>) -> (usize, String, Result<Response<Body>, Error>) {
+    let (parts, mut body) = req.into_parts();
+
+    // Answer CORS preflights ourselves rather than asking the destination:
+    if let Some(cors) = &resolved.options.cors {
+        if cors::preflight_method(&parts.method, &parts.headers).is_some() {
+            return (0, "CORS preflight".to_owned(), Ok(cors.preflight(&parts.headers)))
+        }
+    }
 
-            let response = match file {
-                Ok(file) => {
-                    Response::builder()
-                        .status(200)
-                        .header("Content-Type", mime.unwrap().as_ref())
-                        .body(Body::from(file))
-                        .unwrap()
-                },
-                Err(e) => {
-                    let msg = format!("Weave: Could not read file '{}': {}", path.to_string_lossy(), e);
-                    Response::builder()
-                        .status(404)
-                        .body(Body::from(msg))
-                        .unwrap()
+    // Requests to switch protocols have no body, but we wait on it to
+    // upgrade the connection if a destination agrees to:
+    let mut client_upgrade = if proxy::is_upgrade(&parts) {
+        Some(std::mem::replace(&mut body, Body::empty()).on_upgrade())
+    } else {
+        None
+    };
+
+    let locations: Vec<_> = std::iter::once(&resolved.location).chain(&resolved.fallbacks).collect();
+    let last_idx = locations.len() - 1;
+
+    // If we might need the body more than once (to proxy the request again, render a
+    // template and then fall back, or record the request), we hold on to it so that it
+    // can be reused, unless it's too large. Otherwise we stream it:
+    let may_proxy_again = locations.iter().enumerate().any(|(idx, l)| match l {
+        ResolvedLocation::Url(urls) => idx < last_idx || urls.targets().len() > 1 || recorder.is_some(),
+        ResolvedLocation::Template{..} => idx < last_idx,
+        _ => false
+    });
+    let mut body = Some(body);
+    let buffered_body = if may_proxy_again {
+        match buffer_body(body.take().unwrap(), MAX_BUFFERED_BODY_SIZE).await {
+            Ok(Ok(bytes)) => Some(bytes),
+            Ok(Err(streamed_body)) => {
+                debug!("not buffering the request body, as it is larger than {} bytes", MAX_BUFFERED_BODY_SIZE);
+                body = Some(streamed_body);
+                None
+            },
+            Err(e) => return (0, resolved.location.to_string(), Err(e.into()))
+        }
+    } else {
+        None
+    };
+
+    for (idx, location) in locations.into_iter().enumerate() {
+        let mut desc = None;
+        let res = match location {
+            // Return a status code:
+            ResolvedLocation::HttpStatusCode(code) => {
+                Response::builder()
+                    .status(*code)
+                    .body(Body::empty())
+                    .unwrap()
+            },
+            // Respond with a body given inline:
+            ResolvedLocation::Inline{ code, kind, body } => {
+                Response::builder()
+                    .status(*code)
+                    .header(hyper::header::CONTENT_TYPE, kind.content_type())
+                    .body(Body::from(body.clone()))
+                    .unwrap()
+            },
+            // Redirect somewhere else:
+            ResolvedLocation::Redirect{ code, location } => {
+                let location = match hyper::header::HeaderValue::from_str(location) {
+                    Ok(location) => location,
+                    Err(_) => return (idx, location.to_owned(), Err(err!("'{}' is not a valid redirect location", location)))
+                };
+                Response::builder()
+                    .status(*code)
+                    .header(hyper::header::LOCATION, location)
+                    .body(Body::empty())
+                    .unwrap()
+            },
+            // Serve a recording of the request rather than proxying it:
+            ResolvedLocation::Url(urls) if recorder.is_some_and(|r| r.mode() == Mode::Replay) => {
+                desc = Some(format!("{} (replayed)", location));
+                match &buffered_body {
+                    Some(bytes) => recorder.unwrap().respond(&parts, urls.targets(), bytes),
+                    None => body_too_large()
                 }
-            };
-            Ok(response)
+            },
+            // Proxy to the URI our request matched against:
+            ResolvedLocation::Url(urls) => {
+                // Try another upstream if we can't connect to the one we picked:
+                let mut tried = vec![];
+                let mut outcome = None;
+                while let Some((url, active_guard)) = urls.pick(resolved.options.balance, &tried) {
+                    tried.push(active_guard.index());
+                    let body = match (&buffered_body, body.take()) {
+                        (Some(bytes), _) => Body::from(bytes.clone()),
+                        (None, Some(body)) => body,
+                        (None, None) => return (idx, location.to_string(), Err(err!("the request body was too large to keep, so it can't be sent again")))
+                    };
+                    match proxy::request(client, &parts, body, url, resolved, conn_info, client_upgrade.is_some()).await {
+                        Ok(res) => {
+                            active_guard.succeeded();
+                            let res = if res.status() == StatusCode::SWITCHING_PROTOCOLS && client_upgrade.is_some() {
+                                proxy::splice_upgrade(res, client_upgrade.take().unwrap(), url, active_guard)
+                            } else if let (Some(recorder), Some(bytes)) = (recorder, &buffered_body) {
+                                recorder.save(&parts, url, bytes, res).await
+                            } else if recorder.is_some() {
+                                warn!("not recording the request to {}, as its body is larger than {} bytes", url, MAX_BUFFERED_BODY_SIZE);
+                                res
+                            } else if urls.targets().len() > 1 {
+                                hold_until_streamed(res, active_guard)
+                            } else {
+                                res
+                            };
+                            outcome = Some((url, Ok(res)));
+                            break
+                        },
+                        Err(e) => {
+                            if !e.is_connect() {
+                                outcome = Some((url, Err(e)));
+                                break
+                            }
+                            active_guard.failed(resolved.options.max_fails, &e.to_string());
+                            debug!("error connecting to {}: {}", url, e);
+                            outcome = Some((url, Err(e)));
+                            // We can only try another upstream if we kept a copy of the body:
+                            if buffered_body.is_none() {
+                                break
+                            }
+                        }
+                    }
+                }
+                match outcome.expect("there is always at least one upstream to try") {
+                    (url, Ok(res)) => {
+                        desc = Some(url.to_owned());
+                        res
+                    },
+                    (url, Err(e)) if idx < last_idx => {
+                        debug!("error proxying to {} (trying the next fallback): {}", url, e);
+                        continue
+                    },
+                    (url, Err(e)) => return (idx, url.to_owned(), Err(e.into()))
+                }
+            },
+            // Proxy to the filesystem:
+            ResolvedLocation::FilePath(path) => {
+                files::serve(path, &parts, resolved.options, &resolved.path_tail).await
+            },
+            // Render a template using details of the request:
+            ResolvedLocation::Template{ path, captures } => {
+                let bytes = match &buffered_body {
+                    Some(bytes) => Some(bytes.clone()),
+                    None => match body.take() {
+                        Some(body) => match buffer_body(body, MAX_BUFFERED_BODY_SIZE).await {
+                            Ok(bytes) => bytes.ok(),
+                            Err(e) => return (idx, location.to_string(), Err(e.into()))
+                        },
+                        None => None
+                    }
+                };
+                // Templates need the whole body, which we won't hold in memory if it's too large:
+                match bytes {
+                    Some(bytes) => template::serve(path, &template::RequestData::new(&parts, captures, &bytes)).await,
+                    None => body_too_large()
+                }
+            }
+        };
+
+        // Move on to the next location if we don't like this response:
+        if idx < last_idx && resolved.options.fallback_on.contains(&res.status()) {
+            continue
         }
+        let desc = desc.unwrap_or_else(|| location.to_string());
+        return (idx, desc, Ok(res))
     }
+
+    unreachable!("the last location always returns a response")
+}
+
+/// Read a body into memory if it's no larger than the limit given. If it's larger,
+/// a body is handed back instead that streams the bytes read so far and then the rest.
+async fn buffer_body(mut body: Body, limit: usize) -> Result<Result<Bytes, Body>, hyper::Error> {
+    if body.size_hint().lower() > limit as u64 {
+        return Ok(Err(body))
+    }
+    let mut chunks = vec![];
+    let mut len = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        len += chunk.len();
+        chunks.push(chunk);
+        if len > limit {
+            let read = futures_util::stream::iter(chunks.into_iter().map(Ok));
+            return Ok(Err(Body::wrap_stream(read.chain(body))))
+        }
+    }
+    Ok(Ok(chunks.concat().into()))
+}
+
+/// Respond to say that the request body is too large for us to hold in memory.
+fn body_too_large() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(Body::from(format!("Weave: The request body is larger than {} bytes", MAX_BUFFERED_BODY_SIZE)))
+        .unwrap()
+}
+
+/// Keep an upstream marked as active until the response body from it has been streamed.
+fn hold_until_streamed(res: Response<Body>, active_guard: ActiveGuard) -> Response<Body> {
+    res.map(|body| Body::wrap_stream(body.map(move |chunk| {
+        let _ = &active_guard;
+        chunk
+    })))
 }
@@ -1,41 +1,110 @@
-use hyper::Uri;
-use crate::routes::{ Route };
+use hyper::{ Request, StatusCode, header };
+use crate::routes::{ Route, RouteOptions };
+use crate::headers::{ HeaderRule };
+use crate::cors;
 use crate::location::{ ResolvedLocation };
 
 #[derive(Debug, Clone)]
 pub struct Matcher {
-    routes: Vec<Route>
+    routes: Vec<Route>,
+    /// Used when no single route applies to a request
+    default_options: RouteOptions
 }
 
 impl Matcher {
     /// Build a new matcher given some routes we'd like to match on:
     pub fn new(mut routes: Vec<Route>) -> Matcher {
         routes.sort_by(|a,b| a.src.cmp(&b.src));
-        Matcher { routes }
+        Matcher { routes, default_options: RouteOptions::default() }
     }
 
-    /// Match a Uri against the routes provided. This returns
-    /// the Location to serve up.
-    pub fn resolve(&self, uri: &Uri) -> Option<ResolvedLocation> {
+    /// Match a request against the routes provided. This returns
+    /// the Location to serve up, along with some details about the
+    /// route that was matched.
+    pub fn resolve<B>(&self, req: &Request<B>) -> Option<Resolved<'_>> {
+        let uri = req.uri();
+        let host = uri.host().or_else(|| {
+            req.headers().get(header::HOST).and_then(|host| host.to_str().ok())
+        });
+
         // Find a matching route. We assume routes are ordered and
-        // the first match wins.
-        self.routes.iter().find_map(|route| {
-            route.src.match_uri(uri).map(|matches| {
-                route.dest.resolve(&matches)
+        // the first match wins. If routes match on everything but the
+        // method, we note this and respond with a 405 if nothing else
+        // matches, listing the methods that would have been allowed.
+        let mut method_not_allowed = false;
+        let mut allowed_methods = vec![];
+        // CORS preflights are matched using the method they ask about:
+        let preflight_method = cors::preflight_method(req.method(), req.headers());
+        for route in &self.routes {
+            if let Some(matches) = route.src.match_uri(host, uri) {
+                let method = match (&preflight_method, &route.options.cors) {
+                    (Some(method), Some(_)) => method,
+                    _ => req.method()
+                };
+                if route.src.matches_method(method) {
+                    return Some(Resolved {
+                        location: route.dest.resolve(&matches),
+                        fallbacks: route.fallbacks.iter().map(|dest| dest.resolve(&matches)).collect(),
+                        options: &route.options,
+                        path_tail: matches.path_tail().to_owned(),
+                        request_headers: route.options.request_headers.iter().map(|rule| rule.expand(&matches)).collect(),
+                        response_headers: route.options.response_headers.iter().map(|rule| rule.expand(&matches)).collect()
+                    })
+                }
+                method_not_allowed = true;
+                for method in route.src.methods() {
+                    if !allowed_methods.contains(&method.as_str()) {
+                        allowed_methods.push(method.as_str());
+                    }
+                }
+            }
+        }
+
+        if method_not_allowed {
+            Some(Resolved {
+                location: ResolvedLocation::HttpStatusCode(StatusCode::METHOD_NOT_ALLOWED),
+                fallbacks: vec![],
+                options: &self.default_options,
+                path_tail: String::new(),
+                request_headers: vec![],
+                response_headers: vec![HeaderRule::Set(header::ALLOW, allowed_methods.join(", "))]
             })
-        })
+        } else {
+            None
+        }
     }
 }
 
+/// Where a request should be routed to, and how.
+#[derive(Debug)]
+pub struct Resolved<'a> {
+    /// The location to serve up
+    pub location: ResolvedLocation,
+    /// Locations to try in turn if the location can't serve the request
+    pub fallbacks: Vec<ResolvedLocation>,
+    /// Options for the route that was matched
+    pub options: &'a RouteOptions,
+    /// The part of the request path that comes after the part
+    /// matched by the route
+    pub path_tail: String,
+    /// Changes to make to the request headers, with variables substituted in
+    pub request_headers: Vec<HeaderRule>,
+    /// Changes to make to the response headers, with variables substituted in
+    pub response_headers: Vec<HeaderRule>
+}
+
 #[cfg(test)]
 mod test {
 
-    use hyper::Uri;
-    use crate::location::{ SrcLocation, DestLocation, ResolvedLocation };
+    use hyper::Method;
+    use crate::location::{ SrcLocation, DestLocation, ResolvedLocation, Upstreams };
+    use crate::routes::{ RouteOptions };
 
     use super::*;
+    use crate::cors::{ Cors };
+    use crate::location::{ InlineKind };
 
-    fn url (u: &str) -> Option<ResolvedLocation> { Some(ResolvedLocation::Url(u.to_owned())) }
+    fn url (u: &str) -> Option<ResolvedLocation> { Some(ResolvedLocation::Url(Upstreams::new(u.split(',').map(|u| u.to_owned()).collect()))) }
     fn path (u: &str) -> Option<ResolvedLocation> { Some(ResolvedLocation::FilePath(u.to_owned().into())) }
     fn code (n: u16) -> Option<ResolvedLocation> { Some(ResolvedLocation::HttpStatusCode(hyper::StatusCode::from_u16(n).unwrap())) }
     fn none () -> Option<ResolvedLocation> { None }
@@ -44,14 +113,21 @@ mod test {
             let src: SrcLocation = src.parse().unwrap();
             Route {
                 src: src.clone(),
-                dest: DestLocation::parse(dest, &src).unwrap()
+                dest: DestLocation::parse(dest, &src).unwrap(),
+                fallbacks: vec![],
+                options: RouteOptions::default()
             }
         }).collect();
         let matcher = Matcher::new(routes);
         for (input, expected) in cases {
-            let input_uri: Uri = input.parse().unwrap();
-            let res = matcher.resolve(&input_uri);
-            assert_eq!(res, expected, "original URI: {}", input_uri);
+            // Inputs can optionally start with a method, eg "POST /foo":
+            let (method, uri) = match input.find(' ') {
+                Some(idx) => (input[..idx].parse().unwrap(), &input[idx+1..]),
+                None => (Method::GET, input)
+            };
+            let req = Request::builder().method(method).uri(uri).body(()).unwrap();
+            let res = matcher.resolve(&req).map(|resolved| resolved.location);
+            assert_eq!(res, expected, "original request: {}", input);
         }
     }
 
@@ -150,6 +226,128 @@ mod test {
         )
     }
 
+    #[test]
+    fn several_upstreams() {
+        test_route_matches(
+            vec![
+                ("8080/(foo)", "9090/api/(foo),https://example.com/api/(foo)")
+            ],
+            vec![
+                ("/", none()),
+                ("/users/1?a=b", url("http://localhost:9090/api/users/1?a=b,https://example.com/api/users/1?a=b")),
+            ]
+        )
+    }
+
+    #[test]
+    fn redirects() {
+        let redirect = |n: u16, location: &str| Some(ResolvedLocation::Redirect {
+            code: hyper::StatusCode::from_u16(n).unwrap(),
+            location: location.to_owned()
+        });
+        test_route_matches(
+            vec![
+                ("8080/old", "redirect://301/https://example.com/new"),
+                ("8080/users/(id)", "redirect:///profiles/(id)?tab=info"),
+                ("8080/exact/(id)", "=redirect://https://example.com/(id)"),
+            ],
+            vec![
+                ("/old", redirect(301, "https://example.com/new")),
+                ("/old/foo/bar?a=b", redirect(301, "https://example.com/new/foo/bar?a=b")),
+                ("/users/1", redirect(302, "/profiles/1?tab=info")),
+                ("/users/1/posts?tab=posts&page=2", redirect(302, "/profiles/1/posts?tab=info&page=2")),
+                ("/exact/1/more?a=b", redirect(302, "https://example.com/1")),
+            ]
+        )
+    }
+
+    #[test]
+    fn inline_bodies() {
+        let json = |n: u16, body: &str| Some(ResolvedLocation::Inline {
+            code: hyper::StatusCode::from_u16(n).unwrap(),
+            kind: InlineKind::Json,
+            body: body.to_owned()
+        });
+        test_route_matches(
+            vec![
+                ("=8080/api/users/(id)", r#"json://{"id": (id)}"#),
+                ("8080/api", r#"json://404/{"error": "not found"}"#),
+            ],
+            vec![
+                ("/api/users/1", json(200, r#"{"id": 1}"#)),
+                ("/api/users/1/more", json(404, r#"{"error": "not found"}"#)),
+            ]
+        )
+    }
+
+    #[test]
+    fn templates() {
+        let template = |path: &str, captures: &[(&str, &str)]| Some(ResolvedLocation::Template {
+            path: path.into(),
+            captures: captures.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        });
+        test_route_matches(
+            vec![
+                ("=8080/api/(kind)/(id)", "template://./mocks/(kind).json"),
+                ("8080/pages", "template://./pages"),
+            ],
+            vec![
+                ("/api/users/1", template("./mocks/users.json", &[("kind", "users"), ("id", "1")])),
+                ("/pages/about.html?a=b", template("./pages/about.html", &[])),
+            ]
+        )
+    }
+
+    #[test]
+    fn header_rules_are_expanded() {
+        let src: SrcLocation = "8080/(user)/(id)".parse().unwrap();
+        let options = RouteOptions {
+            request_headers: vec!["set X-User: (user)".parse().unwrap(), "remove Cookie".parse().unwrap()],
+            response_headers: vec!["append X-Id: id-(id)".parse().unwrap()],
+            ..RouteOptions::default()
+        };
+        let matcher = Matcher::new(vec![Route {
+            dest: DestLocation::parse("9090", &src).unwrap(),
+            src,
+            fallbacks: vec![],
+            options
+        }]);
+
+        let req = Request::builder().uri("/bob/123").body(()).unwrap();
+        let resolved = matcher.resolve(&req).unwrap();
+        assert_eq!(resolved.request_headers, vec![
+            "set X-User: bob".parse::<HeaderRule>().unwrap(),
+            "remove Cookie".parse().unwrap()
+        ]);
+        assert_eq!(resolved.response_headers, vec!["append X-Id: id-123".parse::<HeaderRule>().unwrap()]);
+    }
+
+    #[test]
+    fn cors_preflights_match_on_the_method_asked_about() {
+        let src: SrcLocation = "GET=8080/api".parse().unwrap();
+        let route = Route {
+            dest: DestLocation::parse("9090", &src).unwrap(),
+            src,
+            fallbacks: vec![],
+            options: RouteOptions { cors: Some(Cors::default()), ..RouteOptions::default() }
+        };
+        let preflight = |method: &str| Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/api")
+            .header("origin", "http://localhost:3000")
+            .header("access-control-request-method", method)
+            .body(())
+            .unwrap();
+
+        let matcher = Matcher::new(vec![route.clone()]);
+        assert!(matcher.resolve(&preflight("GET")).unwrap().options.cors.is_some());
+        assert!(matcher.resolve(&preflight("POST")).unwrap().options.cors.is_none());
+
+        // Without CORS, preflights are just OPTIONS requests:
+        let matcher = Matcher::new(vec![Route { options: RouteOptions::default(), ..route }]);
+        assert!(matcher.resolve(&preflight("GET")).unwrap().options.cors.is_none());
+    }
+
     #[test]
     fn path_patterns1() {
         test_route_matches(
@@ -374,6 +572,75 @@ mod test {
         )
     }
 
+    #[test]
+    fn host_matching() {
+        test_route_matches(
+            vec![
+                ("8080", "9090/any"),
+                // More specific paths don't win over more specific hosts:
+                ("8080/foo/bar", "9090/specific"),
+                ("api.localhost:8080", "9090/api"),
+                ("=api.localhost:8080/exact", "9090/exact"),
+                ("*.localhost:8080", "9090/wildcard"),
+                // Longer wildcards are more specific:
+                ("*.web.localhost:8080", "9090/web"),
+            ],
+            vec![
+                ("/foo", url("http://localhost:9090/any/foo")),
+                ("/foo/bar", url("http://localhost:9090/specific")),
+                ("http://localhost:8080/foo", url("http://localhost:9090/any/foo")),
+                ("http://example.com/foo", url("http://localhost:9090/any/foo")),
+                ("http://api.localhost:8080/foo/bar", url("http://localhost:9090/api/foo/bar")),
+                ("http://API.localhost/foo", url("http://localhost:9090/api/foo")),
+                ("http://api.localhost/exact", url("http://localhost:9090/exact")),
+                ("http://other.localhost:8080/foo", url("http://localhost:9090/wildcard/foo")),
+                ("http://web.localhost/foo", url("http://localhost:9090/wildcard/foo")),
+                ("http://a.web.localhost/foo", url("http://localhost:9090/web/foo")),
+                ("http://a.b.web.localhost/foo", url("http://localhost:9090/web/foo")),
+            ]
+        )
+    }
+
+    #[test]
+    fn method_matching() {
+        test_route_matches(
+            vec![
+                ("8080/api", "9090"),
+                ("GET,HEAD=8080/api/users", "./users.json"),
+                ("POST==8080/api/users", "statuscode://201"),
+                ("delete=8080/api/users", "9091"),
+                ("PUT=8080/other", "9092"),
+            ],
+            vec![
+                ("GET /api/users", path("./users.json")),
+                ("HEAD /api/users/1", path("./users.json/1")),
+                ("POST /api/users", code(201)),
+                ("DELETE /api/users/1", url("http://localhost:9091/1")),
+                // Falls through to the less specific route:
+                ("POST /api/users/1", url("http://localhost:9090/users/1")),
+                ("PATCH /api/users", url("http://localhost:9090/users")),
+                // Nothing else matches, so method not allowed:
+                ("GET /other", code(405)),
+                ("PUT /other", url("http://localhost:9092/")),
+                ("GET /nope", none()),
+            ]
+        )
+    }
+
+    #[test]
+    fn method_not_allowed_lists_allowed_methods() {
+        let routes = vec![("GET,HEAD=8080/api/users", "./users.json"), ("POST,GET=8080/api", "9090")];
+        let matcher = Matcher::new(routes.into_iter().map(|(src, dest)| {
+            let src: SrcLocation = src.parse().unwrap();
+            Route { dest: DestLocation::parse(dest, &src).unwrap(), src, fallbacks: vec![], options: RouteOptions::default() }
+        }).collect());
+
+        let req = Request::builder().method(Method::DELETE).uri("/api/users").body(()).unwrap();
+        let resolved = matcher.resolve(&req).unwrap();
+        assert_eq!(resolved.location, code(405).unwrap());
+        assert_eq!(resolved.response_headers, vec!["set Allow: GET, HEAD, POST".parse::<HeaderRule>().unwrap()]);
+    }
+
     #[test]
     fn mixed_urls() {
         test_route_matches(
@@ -412,4 +679,20 @@ mod test {
         )
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn fallbacks_are_resolved() {
+        let mut route = crate::routes::parse_route("8080", "./dist").unwrap();
+        route.add_fallback("=./dist/index.html").unwrap();
+        route.add_fallback("9090").unwrap();
+        let matcher = Matcher::new(vec![route]);
+
+        let req = Request::get("/dashboard/42?a=b").body(()).unwrap();
+        let resolved = matcher.resolve(&req).unwrap();
+        assert_eq!(resolved.location, ResolvedLocation::FilePath("./dist/dashboard/42".into()));
+        assert_eq!(resolved.fallbacks, vec![
+            ResolvedLocation::FilePath("./dist/index.html".into()),
+            url("http://localhost:9090/dashboard/42?a=b").unwrap()
+        ]);
+    }
+
+}
@@ -0,0 +1,296 @@
+use std::io;
+use std::net::{ IpAddr, SocketAddr };
+use std::time::Instant;
+use hyper::{ Body, Request, Response, StatusCode, header::{ self, HeaderMap, HeaderName, HeaderValue }, http::request };
+use hyper::upgrade::{ OnUpgrade };
+use tokio::io::{ AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt };
+use futures_util::{ join, future::try_join };
+use crate::client::{ HttpClient };
+use crate::routes::{ HostHeader };
+use crate::matcher::{ Resolved };
+use crate::headers;
+
+use log::{ debug, info, warn };
+
+/// Headers that tell destinations about the client and what it made the request to.
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PORT: &str = "x-forwarded-port";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const FORWARDING: &[&str] = &["forwarded", X_FORWARDED_FOR, X_FORWARDED_HOST, X_FORWARDED_PORT, X_FORWARDED_PROTO];
+
+/// Details about the connection that a request arrived on.
+#[derive(Debug,Clone,Copy)]
+pub struct ConnectionInfo {
+    /// The address of the client.
+    pub peer_addr: SocketAddr,
+    /// The port that the client connected to.
+    pub port: u16,
+    /// Whether the connection is secured using TLS.
+    pub secure: bool
+}
+
+/// Headers that only apply to a single connection, and so aren't passed on
+/// (see RFC 7230 section 6.1). Headers named in `Connection` are removed too.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade"
+];
+
+/// Proxy a request on to the URL provided, handing back the response. The request
+/// details are borrowed so that they can be used again if the response isn't suitable.
+/// If `upgrade` is true, the headers asking to switch protocols are passed on too.
+pub async fn request(client: &HttpClient, parts: &request::Parts, body: Body, url: &str, resolved: &Resolved<'_>, conn: &ConnectionInfo, upgrade: bool) -> Result<Response<Body>, hyper::Error> {
+    let options = resolved.options;
+    let mut req = Request::new(body);
+    *req.method_mut() = parts.method.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    remove_hop_by_hop(req.headers_mut());
+    if upgrade {
+        copy_upgrade_headers(&parts.headers, req.headers_mut());
+    }
+    // Set the request URI to our new destination:
+    *req.uri_mut() = url.parse().unwrap();
+    // Work out the host header (it's set according to URI if not present):
+    let original_host = parts.headers.get(header::HOST).cloned()
+        .or_else(|| parts.uri.authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok()));
+    match &options.host_header {
+        HostHeader::Upstream => { req.headers_mut().remove(header::HOST); },
+        HostHeader::Preserve => {},
+        HostHeader::Value(host) => { req.headers_mut().insert(header::HOST, host.clone()); }
+    }
+    // Let the destination know who the request is from and what it was made to:
+    add_forwarding_headers(req.headers_mut(), conn, original_host, options.trust_forwarded_headers);
+    // Make any changes to the headers that the route asks for:
+    headers::apply(&resolved.request_headers, req.headers_mut());
+    // Proxy the request through and pass back the response:
+    let mut res = client.request(req).await?;
+    if res.status() != StatusCode::SWITCHING_PROTOCOLS {
+        remove_hop_by_hop(res.headers_mut());
+    }
+    Ok(res)
+}
+
+/// Is this a request to switch protocols (eg a WebSocket handshake)?
+pub fn is_upgrade(parts: &request::Parts) -> bool {
+    parts.headers.contains_key(header::UPGRADE) && connection_tokens(&parts.headers).any(|t| t.eq_ignore_ascii_case("upgrade"))
+}
+
+/// Given a `101 Switching Protocols` response from a destination, and the client
+/// connection waiting to be upgraded, hand back the response to send to the client.
+/// Once it's been sent, the two connections are spliced together until either side
+/// closes. `guard` is held on to until then.
+pub fn splice_upgrade(res: Response<Body>, client: OnUpgrade, url: &str, guard: impl Send + 'static) -> Response<Body> {
+    let (mut parts, body) = res.into_parts();
+    let upstream = body.on_upgrade();
+
+    let upgrade_headers = parts.headers.clone();
+    remove_hop_by_hop(&mut parts.headers);
+    copy_upgrade_headers(&upgrade_headers, &mut parts.headers);
+
+    let url = url.to_owned();
+    tokio::spawn(async move {
+        let _guard = guard;
+        let (client, upstream) = match try_join(client, upstream).await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                warn!("[upgrade] error upgrading connection to {}: {}", url, e);
+                return
+            }
+        };
+
+        let started = Instant::now();
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+        let mut sent = 0;
+        let mut received = 0;
+        let (sent_res, received_res) = join!(
+            copy_counting(&mut client_read, &mut upstream_write, &mut sent),
+            copy_counting(&mut upstream_read, &mut client_write, &mut received)
+        );
+        if let Err(e) = sent_res.and(received_res) {
+            debug!("[upgrade] error streaming to or from {}: {}", url, e);
+        }
+        info!("[upgrade] session with {} closed after {:#?} ({} bytes sent, {} bytes received)",
+              url, started.elapsed(), sent, received);
+    });
+
+    Response::from_parts(parts, Body::empty())
+}
+
+/// Copy bytes from one side to the other until there are no more, counting them as we go.
+async fn copy_counting<R, W>(read: &mut R, write: &mut W, count: &mut u64) -> io::Result<()>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let mut buf = vec![0; 16 * 1024];
+    loop {
+        let n = read.read(&mut buf).await?;
+        if n == 0 {
+            break
+        }
+        write.write_all(&buf[..n]).await?;
+        *count += n as u64;
+    }
+    write.shutdown().await
+}
+
+/// Tell the destination about the client and what it made the request to. Forwarding
+/// headers that the request already has are added to if they're trusted (the client
+/// is itself a proxy), and replaced otherwise.
+fn add_forwarding_headers(headers: &mut HeaderMap, conn: &ConnectionInfo, host: Option<HeaderValue>, trusted: bool) {
+    if !trusted {
+        for name in FORWARDING {
+            headers.remove(*name);
+        }
+    }
+
+    let proto = if conn.secure { "https" } else { "http" };
+    let ip = conn.peer_addr.ip();
+    append_value(headers, X_FORWARDED_FOR, &ip.to_string());
+    headers.entry(X_FORWARDED_PROTO).or_insert(HeaderValue::from_static(proto));
+    headers.entry(X_FORWARDED_PORT).or_insert(HeaderValue::from(conn.port));
+    if let Some(host) = &host {
+        headers.entry(X_FORWARDED_HOST).or_insert(host.clone());
+    }
+
+    // RFC 7239 describes a single header for all of the above:
+    let mut forwarded = format!("for={}", forwarded_node(ip));
+    if let Some(host) = host.as_ref().and_then(|host| host.to_str().ok()) {
+        forwarded.push_str(&format!(";host={}", forwarded_value(host)));
+    }
+    forwarded.push_str(&format!(";proto={}", proto));
+    append_value(headers, header::FORWARDED.as_str(), &forwarded);
+}
+
+/// Add a value to a comma separated header, combining any existing values into one.
+fn append_value(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    let mut values: Vec<&str> = headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect();
+    values.push(value);
+    if let Ok(combined) = HeaderValue::from_str(&values.join(", ")) {
+        headers.insert(name, combined);
+    }
+}
+
+/// IPv6 addresses are bracketed and quoted in `Forwarded` headers.
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip)
+    }
+}
+
+/// Values in `Forwarded` headers are quoted unless they're a valid token.
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_owned()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Remove any headers that only apply to the connection that they arrived on.
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = connection_tokens(headers)
+        .filter_map(|token| HeaderName::from_bytes(token.as_bytes()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+/// Put back the headers needed to switch protocols.
+fn copy_upgrade_headers(from: &HeaderMap, to: &mut HeaderMap) {
+    if let Some(upgrade) = from.get(header::UPGRADE) {
+        to.insert(header::UPGRADE, upgrade.clone());
+        to.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+}
+
+/// The comma separated tokens given in `Connection` headers.
+fn connection_tokens(headers: &HeaderMap) -> impl Iterator<Item=&str> {
+    headers.get_all(header::CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn hop_by_hop_headers_are_removed() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", HeaderValue::from_static("keep-alive, X-Private"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("transfer-encoding", HeaderValue::from_static("chunked"));
+        headers.insert("x-private", HeaderValue::from_static("secret"));
+        headers.insert("x-public", HeaderValue::from_static("hello"));
+        remove_hop_by_hop(&mut headers);
+        let names: Vec<_> = headers.keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, vec!["x-public"]);
+    }
+
+    #[test]
+    fn forwarding_headers_are_added() {
+        let conn = ConnectionInfo {
+            peer_addr: "[2001:db8::1]:51000".parse().unwrap(),
+            port: 8443,
+            secure: true
+        };
+        let incoming = || {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+            headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+            headers.insert("forwarded", HeaderValue::from_static("for=203.0.113.7"));
+            headers
+        };
+        let host = Some(HeaderValue::from_static("example.com:8443"));
+
+        // Untrusted forwarding headers are replaced:
+        let mut headers = incoming();
+        add_forwarding_headers(&mut headers, &conn, host.clone(), false);
+        assert_eq!(headers["x-forwarded-for"], "2001:db8::1");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-port"], "8443");
+        assert_eq!(headers["x-forwarded-host"], "example.com:8443");
+        assert_eq!(headers["forwarded"], "for=\"[2001:db8::1]\";host=\"example.com:8443\";proto=https");
+
+        // Trusted ones are added to:
+        let mut headers = incoming();
+        add_forwarding_headers(&mut headers, &conn, host, true);
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7, 2001:db8::1");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["forwarded"], "for=203.0.113.7, for=\"[2001:db8::1]\";host=\"example.com:8443\";proto=https");
+    }
+
+    #[test]
+    fn upgrade_requests_are_detected() {
+        let upgrade = |connection: &str| {
+            let (parts, _) = Request::get("/ws")
+                .header("Connection", connection)
+                .header("Upgrade", "websocket")
+                .body(())
+                .unwrap()
+                .into_parts();
+            is_upgrade(&parts)
+        };
+        assert!(upgrade("Upgrade"));
+        assert!(upgrade("keep-alive, upgrade"));
+        assert!(!upgrade("keep-alive"));
+    }
+
+}
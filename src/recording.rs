@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use futures_util::{ stream, StreamExt };
+use hyper::{ Body, Response, StatusCode, HeaderMap, Method, header, body::HttpBody, http::request };
+use hyper::header::{ HeaderName, HeaderValue };
+use serde::{ Serialize, Deserialize };
+use crate::errors::{ Error };
+use crate::util::{ fnv1a };
+
+use log::{ warn };
+
+/// Responses with bodies larger than this are passed on without being recorded.
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+/// Record the requests proxied to HTTP destinations and the responses to them,
+/// or serve those recordings back instead of proxying.
+#[derive(Debug)]
+pub struct Recorder {
+    dir: PathBuf,
+    mode: Mode,
+    /// Used to keep recordings made in the same millisecond in order.
+    count: AtomicUsize,
+    /// What requests have to have in common with a recording to be served it.
+    match_on: Vec<MatchOn>,
+    /// Recordings to replay, grouped by what they match on. Each group is
+    /// served in turn, repeating the last one once we run out.
+    replays: Mutex<HashMap<String, (usize, Vec<Recording>)>>
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Mode {
+    Record,
+    Replay
+}
+
+/// The parts of a request that we can match recordings on.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum MatchOn {
+    Method,
+    Path,
+    /// Query params are matched regardless of their order.
+    Query,
+    /// Bodies are matched using a hash of their contents.
+    Body
+}
+
+impl FromStr for MatchOn {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<MatchOn, Error> {
+        match &*s.trim().to_ascii_lowercase() {
+            "method" => Ok(MatchOn::Method),
+            "path" => Ok(MatchOn::Path),
+            "query" => Ok(MatchOn::Query),
+            "body" => Ok(MatchOn::Body),
+            _ => Err(err!("'{}' is not something that requests can be matched on; expected \
+                           'method', 'path', 'query' or 'body'", s.trim()))
+        }
+    }
+}
+
+/// Parse a comma separated list of things to match requests on.
+pub fn parse_match_on(s: &str) -> Result<Vec<MatchOn>, Error> {
+    s.split(',').map(|part| part.parse()).collect()
+}
+
+/// A request and the response to it, as saved to disk.
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+struct Recording {
+    request: RecordedRequest,
+    response: RecordedResponse
+}
+
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+struct RecordedRequest {
+    method: String,
+    /// The host and port of the destination that the request was proxied to.
+    authority: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+    body: RecordedBody
+}
+
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: RecordedBody
+}
+
+/// Bodies are saved as text where possible, and base64 encoded otherwise.
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+struct RecordedBody {
+    text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>
+}
+
+impl RecordedBody {
+    fn new(bytes: &[u8]) -> RecordedBody {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => RecordedBody { text: text.to_owned(), encoding: None },
+            Err(_) => RecordedBody { text: base64::encode(bytes), encoding: Some("base64".to_owned()) }
+        }
+    }
+    fn bytes(&self) -> Result<Vec<u8>, Error> {
+        match self.encoding.as_deref() {
+            None => Ok(self.text.clone().into_bytes()),
+            Some("base64") => base64::decode(&self.text).map_err(|e| err!("the body is not valid base64: {}", e)),
+            Some(encoding) => Err(err!("'{}' is not a known body encoding", encoding))
+        }
+    }
+}
+
+impl Recorder {
+    /// Save recordings to the directory provided, creating it if need be.
+    pub fn record(dir: impl AsRef<Path>) -> Result<Recorder, Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(|e| err!("could not create the directory '{}': {}", dir.display(), e))?;
+        Ok(Recorder::new(dir, Mode::Record, vec![], HashMap::new()))
+    }
+
+    /// Load recordings from the directory provided, ready to replay them.
+    pub fn replay(dir: impl AsRef<Path>, match_on: Vec<MatchOn>) -> Result<Recorder, Error> {
+        let dir = dir.as_ref();
+        let entries = fs::read_dir(dir).map_err(|e| err!("could not read recordings from '{}': {}", dir.display(), e))?;
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        // File names start with the time they were recorded, so this puts them in order:
+        paths.sort();
+
+        let mut replays: HashMap<String, (usize, Vec<Recording>)> = HashMap::new();
+        for path in paths {
+            let recording = fs::read_to_string(&path)
+                .map_err(Error::from)
+                .and_then(|json| Ok(serde_json::from_str::<Recording>(&json)?))
+                .and_then(|recording| recording.response.body.bytes().map(|_| recording))
+                .map_err(|e| err!("the recording '{}' is invalid: {}", path.display(), e))?;
+            let body = recording.request.body.bytes()?;
+            let request = &recording.request;
+            let key = match_key(&match_on, &request.authority, &request.method, &request.path, &request.query, &body);
+            replays.entry(key).or_default().1.push(recording);
+        }
+        Ok(Recorder::new(dir, Mode::Replay, match_on, replays))
+    }
+
+    fn new(dir: &Path, mode: Mode, match_on: Vec<MatchOn>, replays: HashMap<String, (usize, Vec<Recording>)>) -> Recorder {
+        Recorder {
+            dir: dir.to_owned(),
+            mode,
+            count: AtomicUsize::new(0),
+            match_on,
+            replays: Mutex::new(replays)
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// How many recordings are there to replay?
+    pub fn recording_count(&self) -> usize {
+        self.replays.lock().unwrap().values().map(|(_, recordings)| recordings.len()).sum()
+    }
+
+    /// Save a request that was proxied to the URL given, and the response to it. The
+    /// response is handed back, and the recording is saved once its body has been
+    /// streamed to the client in full. Failing to save the recording is logged.
+    pub async fn save(&self, req: &request::Parts, url: &str, body: &[u8], res: Response<Body>) -> Response<Body> {
+        let (parts, res_body) = res.into_parts();
+
+        let (authority, path, query) = split_url(url);
+        let recording = Recording {
+            request: RecordedRequest {
+                method: req.method.to_string(),
+                authority: authority.to_owned(),
+                path: path.to_owned(),
+                query: query.to_owned(),
+                headers: headers_to_vec(&req.headers),
+                body: RecordedBody::new(body)
+            },
+            response: RecordedResponse {
+                status: parts.status.as_u16(),
+                headers: headers_to_vec(&parts.headers),
+                body: RecordedBody::new(b"")
+            }
+        };
+
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        let count = self.count.fetch_add(1, Ordering::Relaxed) % 10000;
+        let file_name = format!("{:013}-{:04}-{}-{}{}.json", millis, count, req.method, file_name_safe(authority), file_name_safe(path));
+        let pending = PendingRecording {
+            recording,
+            file_path: self.dir.join(file_name),
+            url: url.to_owned(),
+            body: Vec::new()
+        };
+
+        // Nobody will wait on an empty body (or the body of a response to a HEAD
+        // request, which is never sent), so we save those right away:
+        if req.method == Method::HEAD || res_body.size_hint().exact() == Some(0) {
+            pending.write().await;
+            return Response::from_parts(parts, res_body)
+        }
+
+        // Pass each chunk of the body on as it arrives, keeping hold of a copy until we
+        // have it all (unless it's too large to record, or fails), and then save it. Once
+        // as much as the Content-Length has been sent, the body isn't asked for any more,
+        // so we save it before handing over the last chunk in that case:
+        let expected_len = parts.headers.get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<usize>().ok());
+        let chunks = stream::unfold((res_body, Some(pending)), move |(mut res_body, mut pending)| async move {
+            match res_body.next().await {
+                Some(Ok(chunk)) => {
+                    if let Some(p) = &mut pending {
+                        if p.body.len() + chunk.len() > MAX_BODY_SIZE {
+                            warn!("not recording the response from {}, as its body is larger than {} bytes", p.url, MAX_BODY_SIZE);
+                            pending = None;
+                        } else {
+                            p.body.extend_from_slice(&chunk);
+                        }
+                    }
+                    if let Some(p) = pending.take() {
+                        if Some(p.body.len()) == expected_len {
+                            p.write().await;
+                        } else {
+                            pending = Some(p);
+                        }
+                    }
+                    Some((Ok(chunk), (res_body, pending)))
+                },
+                Some(Err(e)) => Some((Err(e), (res_body, None))),
+                None => {
+                    if let Some(pending) = pending {
+                        pending.write().await;
+                    }
+                    None
+                }
+            }
+        });
+        Response::from_parts(parts, Body::wrap_stream(chunks))
+    }
+
+    /// Respond to a request for the URLs given with a matching recording. A destination
+    /// with several upstreams has a URL for each, and a recording of any of them will
+    /// do. If there isn't one, we respond with a 404 so that any fallbacks are tried.
+    pub fn respond(&self, req: &request::Parts, urls: &[String], body: &[u8]) -> Response<Body> {
+        let keys: Vec<_> = urls.iter().map(|url| {
+            let (authority, path, query) = split_url(url);
+            match_key(&self.match_on, authority, req.method.as_str(), path, query, body)
+        }).collect();
+
+        let mut replays = self.replays.lock().unwrap();
+        let recording = match keys.iter().find(|key| replays.contains_key(*key)).and_then(|key| replays.get_mut(key)) {
+            Some((next, recordings)) => {
+                let recording = &recordings[(*next).min(recordings.len() - 1)];
+                *next += 1;
+                recording
+            },
+            None => {
+                return Response::builder()
+                    .status(404)
+                    .body(Body::from(format!("Weave: No recording matches {} {}", req.method, urls.join(" or "))))
+                    .unwrap()
+            }
+        };
+
+        let mut res = Response::new(Body::from(recording.response.body.bytes().expect("bodies are checked when loaded")));
+        *res.status_mut() = StatusCode::from_u16(recording.response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        for (name, value) in &recording.response.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_str(name), HeaderValue::from_str(value)) {
+                res.headers_mut().append(name, value);
+            }
+        }
+        res
+    }
+}
+
+/// A recording that's waiting on the rest of the response body.
+struct PendingRecording {
+    recording: Recording,
+    file_path: PathBuf,
+    url: String,
+    body: Vec<u8>
+}
+
+impl PendingRecording {
+    async fn write(mut self) {
+        self.recording.response.body = RecordedBody::new(&self.body);
+        let json = serde_json::to_string_pretty(&self.recording).expect("recordings can always be serialized");
+        if let Err(e) = tokio::fs::write(&self.file_path, json).await {
+            warn!("could not save the recording '{}': {}", self.file_path.display(), e);
+        }
+    }
+}
+
+/// Build a string which is the same for requests that match each other.
+/// Requests only ever match recordings of requests to the same destination.
+fn match_key(match_on: &[MatchOn], authority: &str, method: &str, path: &str, query: &str, body: &[u8]) -> String {
+    let mut key = authority.to_ascii_lowercase();
+    key.push('\n');
+    for part in match_on {
+        match part {
+            MatchOn::Method => key.push_str(method),
+            MatchOn::Path => key.push_str(path),
+            MatchOn::Query => {
+                let mut params: Vec<_> = query.split('&').filter(|p| !p.is_empty()).collect();
+                params.sort_unstable();
+                key.push_str(&params.join("&"));
+            },
+            MatchOn::Body => key.push_str(&format!("{:016x}", fnv1a(body)))
+        }
+        key.push('\n');
+    }
+    key
+}
+
+/// Split a URL into its authority (host and port), path and query.
+fn split_url(url: &str) -> (&str, &str, &str) {
+    let after_scheme = &url[url.find("://").map(|idx| idx + 3).unwrap_or(0)..];
+    let authority_end = after_scheme.find(['/', '?']).unwrap_or(after_scheme.len());
+    let (authority, rest) = after_scheme.split_at(authority_end);
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+    (authority, if path.is_empty() { "/" } else { path }, query)
+}
+
+fn headers_to_vec(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers.iter()
+        .filter_map(|(name, value)| value.to_str().ok().map(|value| (name.to_string(), value.to_owned())))
+        .collect()
+}
+
+/// Turn a path into something that can be part of a file name.
+fn file_name_safe(path: &str) -> String {
+    path.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' { c } else { '-' })
+        .take(100)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::test_support::{ temp_dir, body_string };
+    use hyper::Request;
+
+    fn parts(method: &str) -> request::Parts {
+        Request::builder().method(method).uri("/").body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn urls_are_split_into_an_authority_path_and_query() {
+        assert_eq!(split_url("http://localhost:9090/api/users?a=b"), ("localhost:9090", "/api/users", "a=b"));
+        assert_eq!(split_url("https://example.com"), ("example.com", "/", ""));
+        assert_eq!(split_url("https://example.com?a=b"), ("example.com", "/", "a=b"));
+        assert_eq!(match_key(&[MatchOn::Query], "a", "GET", "/", "b=2&a=1", b""), match_key(&[MatchOn::Query], "a", "GET", "/", "a=1&b=2", b""));
+        assert_ne!(match_key(&[], "a:1", "GET", "/", "", b""), match_key(&[], "a:2", "GET", "/", "", b""));
+    }
+
+    #[tokio::test]
+    async fn recordings_can_be_replayed() {
+        let dir = temp_dir();
+        let recorder = Recorder::record(&dir).unwrap();
+        let url = "http://localhost:9090/api/users?page=1";
+        for (method, body, status) in [("POST", "one", 201), ("POST", "two", 200), ("GET", "", 200)] {
+            let res = Response::builder().status(status).header("x-test", "yes").body(Body::from(format!("{} {}", method, body))).unwrap();
+            let res = recorder.save(&parts(method), url, body.as_bytes(), res).await;
+            assert_eq!(body_string(res).await, format!("{} {}", method, body));
+        }
+
+        // Recordings are served in turn, repeating the last:
+        let replayer = Recorder::replay(&dir, vec![MatchOn::Method, MatchOn::Path]).unwrap();
+        assert_eq!(replayer.recording_count(), 3);
+        let res = replayer.respond(&parts("POST"), &[url.to_owned()], b"");
+        assert_eq!(res.status(), 201);
+        assert_eq!(res.headers()["x-test"], "yes");
+        assert_eq!(body_string(res).await, "POST one");
+        assert_eq!(body_string(replayer.respond(&parts("POST"), &[url.to_owned()], b"")).await, "POST two");
+        assert_eq!(body_string(replayer.respond(&parts("POST"), &[url.to_owned()], b"")).await, "POST two");
+        assert_eq!(body_string(replayer.respond(&parts("GET"), &["http://localhost:9090/api/users".to_owned()], b"")).await, "GET ");
+        assert_eq!(replayer.respond(&parts("GET"), &["http://localhost:9090/api/posts".to_owned()], b"").status(), 404);
+
+        // Bodies and queries can be matched on too:
+        let replayer = Recorder::replay(&dir, vec![MatchOn::Method, MatchOn::Query, MatchOn::Body]).unwrap();
+        assert_eq!(body_string(replayer.respond(&parts("POST"), &[url.to_owned()], b"two")).await, "POST two");
+        assert_eq!(replayer.respond(&parts("POST"), &[url.to_owned()], b"three").status(), 404);
+        assert_eq!(replayer.respond(&parts("GET"), &["http://localhost:9090/api/users?page=2".to_owned()], b"").status(), 404);
+    }
+
+    #[tokio::test]
+    async fn recordings_are_kept_apart_by_destination() {
+        let dir = temp_dir();
+        let recorder = Recorder::record(&dir).unwrap();
+        for url in ["http://localhost:8080/api/users", "https://example.com/api/users"] {
+            // Like hyper, only read as much of the body as the Content-Length says is there:
+            let res = Response::builder().header("content-length", url.len()).body(Body::from(url)).unwrap();
+            let mut body = recorder.save(&parts("GET"), url, b"", res).await.into_body();
+            assert_eq!(body.data().await.unwrap().unwrap(), url);
+        }
+
+        let mut file_names: Vec<_> = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        file_names.sort();
+        assert_eq!(file_names.len(), 2);
+        assert!(file_names[0].ends_with("-GET-localhost-8080-api-users.json"));
+        assert!(file_names[1].ends_with("-GET-example.com-api-users.json"));
+
+        let replayer = Recorder::replay(&dir, vec![MatchOn::Method, MatchOn::Path]).unwrap();
+        for url in ["http://localhost:8080/api/users", "https://example.com/api/users"] {
+            assert_eq!(body_string(replayer.respond(&parts("GET"), &[url.to_owned()], b"")).await, url);
+        }
+        assert_eq!(replayer.respond(&parts("GET"), &["http://localhost:9090/api/users".to_owned()], b"").status(), 404);
+    }
+
+    #[tokio::test]
+    async fn recordings_from_any_upstream_can_be_replayed() {
+        let dir = temp_dir();
+        let recorder = Recorder::record(&dir).unwrap();
+        let res = recorder.save(&parts("GET"), "http://localhost:9091/api/users", b"", Response::new(Body::from("second"))).await;
+        assert_eq!(body_string(res).await, "second");
+
+        let replayer = Recorder::replay(&dir, vec![MatchOn::Method, MatchOn::Path]).unwrap();
+        let urls = vec!["http://localhost:9090/api/users".to_owned(), "http://localhost:9091/api/users".to_owned()];
+        assert_eq!(body_string(replayer.respond(&parts("GET"), &urls, b"")).await, "second");
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_passed_on_without_being_recorded() {
+        let dir = temp_dir();
+        let recorder = Recorder::record(&dir).unwrap();
+        let res = Response::new(Body::from(vec![b'a'; MAX_BODY_SIZE + 1]));
+        let res = recorder.save(&parts("GET"), "http://localhost:9090/big", b"", res).await;
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap().len(), MAX_BODY_SIZE + 1);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+}
@@ -1,6 +1,14 @@
+use std::fmt;
+use std::str::FromStr;
 use std::net::{ SocketAddr };
+use hyper::StatusCode;
+use hyper::header::HeaderValue;
 use crate::errors::{ Error };
-use crate::location::{ SrcLocation, DestLocation, Protocol };
+use crate::location::{ SrcLocation, DestLocation, Protocol, Upstreams, Balance };
+use crate::tls::{ TlsFiles };
+use crate::health::{ HealthCheck };
+use crate::headers::{ HeaderRule };
+use crate::cors::{ Cors };
 
 /// Take some args and hand back a vector of Routes we've parsed out of them,
 /// plus an Iterator of unused args:
@@ -12,10 +20,13 @@ pub fn from_args(args: &[String]) -> Result<(Vec<Route>, &[String]), Error> {
         .find(|(_,arg)| arg.starts_with("-"))
         .map_or_else(|| (args, &[][..]), |(n,_)| args.split_at(n));
 
-    // The last argument shouldn't be "and":
-    if args.last().map_or(false, |l| l == "and") {
+    // The last argument shouldn't be "and" or "or":
+    if args.last().is_some_and(|l| l == "and") {
         return Err(err!("'and' not followed by a subsequent route"));
     }
+    if args.last().is_some_and(|l| l == "or") {
+        return Err(err!("'or' not followed by a fallback destination"));
+    }
 
     // Iterate the potential route args to build routes.
     let mut routes = vec![];
@@ -70,29 +81,22 @@ pub fn from_args(args: &[String]) -> Result<(Vec<Route>, &[String]), Error> {
         let dest_str = &*args[idx+2];
         idx += 3;
 
-        // Parse the source location:
-        let src = match SrcLocation::parse(src_str.clone()) {
-            Ok(src) => src,
-            Err(e) => { return Err(err!("'{}' is not a valid source location: {}", src_str, e)) }
-        };
-
         // Expect "to" to separate src and dest:
         if to_str != "to" {
             return Err(err!("'{}' should be followed by 'to' and \
                              then a destination location", src_str))
         }
 
-        // Parse the dest location:
-        let dest = match DestLocation::parse(dest_str, &src) {
-            Ok(dest) => dest,
-            Err(e) => { return Err(err!("'{}' is not a valid destination location: {}", dest_str, e)) }
-        };
+        // Parse the locations to form a new route:
+        let mut route = parse_route(src_str, dest_str)?;
+
+        // Any number of fallback destinations can follow, each preceded by "or":
+        while idx + 1 < args.len() && args[idx] == "or" {
+            route.add_fallback(&args[idx+1])?;
+            idx += 2;
+        }
 
-        // Push these to a new route:
-        routes.push(Route {
-            src,
-            dest
-        });
+        routes.push(route);
 
     }
 
@@ -101,23 +105,177 @@ pub fn from_args(args: &[String]) -> Result<(Vec<Route>, &[String]), Error> {
     Ok(( routes, rest ))
 }
 
+/// Parse a single route given the source and destination strings. Routes
+/// provided on the CLI and in config files are both parsed using this.
+pub fn parse_route(src_str: &str, dest_str: &str) -> Result<Route, Error> {
+
+    // Parse the source location:
+    let src = match SrcLocation::parse(src_str) {
+        Ok(src) => src,
+        Err(e) => { return Err(err!("'{}' is not a valid source location: {}", src_str, e)) }
+    };
+
+    // Parse the dest location:
+    let dest = match DestLocation::parse(dest_str, &src) {
+        Ok(dest) => dest,
+        Err(e) => { return Err(err!("'{}' is not a valid destination location: {}", dest_str, e)) }
+    };
+
+    Ok(Route {
+        src,
+        dest,
+        fallbacks: vec![],
+        options: RouteOptions::default()
+    })
+}
+
 #[derive(Debug,Clone,PartialEq)]
 pub struct Route {
     pub src: SrcLocation,
-    pub dest: DestLocation
+    pub dest: DestLocation,
+    /// Destinations to try in order if the ones before them can't serve a request.
+    pub fallbacks: Vec<DestLocation>,
+    pub options: RouteOptions
+}
+
+/// Options that change how a route behaves. These can
+/// be provided alongside routes in a config file.
+#[derive(Debug,Clone,PartialEq)]
+pub struct RouteOptions {
+    /// The certificate and key to use for an HTTPS source.
+    pub tls: Option<TlsFiles>,
+    /// The `Cache-Control` header to send back with files.
+    pub cache_control: Option<HeaderValue>,
+    /// List the contents of directories that don't contain an index file.
+    pub directory_listing: bool,
+    /// Try the next fallback destination if a response has one of these statuses.
+    pub fallback_on: Vec<StatusCode>,
+    /// How to spread requests across destinations that list several upstreams.
+    pub balance: Balance,
+    /// Consecutive failures to reach a destination before it's considered unhealthy.
+    pub max_fails: usize,
+    /// Probe destinations to check that they are healthy.
+    pub health_check: Option<HealthCheck>,
+    /// The `Host` header to send to HTTP destinations.
+    pub host_header: HostHeader,
+    /// Add to forwarding headers that requests arrive with, rather than replacing them.
+    pub trust_forwarded_headers: bool,
+    /// Changes to make to the headers of requests that are proxied.
+    pub request_headers: Vec<HeaderRule>,
+    /// Changes to make to the headers of responses.
+    pub response_headers: Vec<HeaderRule>,
+    /// Answer CORS preflight requests, and add CORS headers to responses.
+    pub cors: Option<Cors>
+}
+
+impl Default for RouteOptions {
+    fn default() -> RouteOptions {
+        RouteOptions {
+            tls: None,
+            cache_control: None,
+            directory_listing: false,
+            fallback_on: vec![StatusCode::NOT_FOUND],
+            balance: Balance::default(),
+            max_fails: 3,
+            health_check: None,
+            host_header: HostHeader::Upstream,
+            trust_forwarded_headers: false,
+            request_headers: vec![],
+            response_headers: vec![],
+            cors: None
+        }
+    }
+}
+
+/// What to send as the `Host` header when proxying a request.
+#[derive(Debug,Clone,PartialEq)]
+pub enum HostHeader {
+    /// The host and port of the destination.
+    Upstream,
+    /// Whatever the request was given.
+    Preserve,
+    /// The value provided.
+    Value(HeaderValue)
+}
+
+impl FromStr for HostHeader {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<HostHeader, Error> {
+        match s {
+            "upstream" => Ok(HostHeader::Upstream),
+            "preserve" => Ok(HostHeader::Preserve),
+            _ => HeaderValue::from_str(s)
+                .map(HostHeader::Value)
+                .map_err(|_| err!("'{}' is not a valid host header", s))
+        }
+    }
+}
+
+/// Parse a comma separated list of status codes, eg "404,502".
+pub fn parse_status_codes(codes: &str) -> Result<Vec<StatusCode>, Error> {
+    codes.split(',').map(|code| {
+        let code = code.trim();
+        code.parse().map_err(|_| err!("'{}' is not a valid status code", code))
+    }).collect()
+}
+
+/// The path to probe HTTP destinations on, which always starts with '/'.
+pub fn health_check_path(path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_owned()
+    } else {
+        format!("/{}", path)
+    }
 }
 
 impl Route {
+    /// Add a destination to try if the destinations before it can't serve a request.
+    pub fn add_fallback(&mut self, dest_str: &str) -> Result<(), Error> {
+        if self.protocol() == Protocol::Tcp {
+            return Err(err!("'{}' cannot be used as a fallback; {} routes cannot have fallbacks", dest_str, Protocol::Tcp))
+        }
+        let dest = match DestLocation::parse(dest_str, &self.src) {
+            Ok(dest) => dest,
+            Err(e) => { return Err(err!("'{}' is not a valid fallback location: {}", dest_str, e)) }
+        };
+        self.fallbacks.push(dest);
+        Ok(())
+    }
+    /// Carry on using what we know about the upstreams of previously served routes
+    /// (which are unhealthy, and how busy each one is) for any destinations whose
+    /// upstreams haven't changed. Routes with different health checks start afresh,
+    /// so that the new checks are used.
+    pub fn keep_upstream_state_from(&mut self, previous: &[Route]) {
+        let previous_dests: Vec<&DestLocation> = previous.iter()
+            .filter(|route| route.options.health_check == self.options.health_check && route.options.max_fails == self.options.max_fails)
+            .flat_map(|route| std::iter::once(&route.dest).chain(&route.fallbacks))
+            .collect();
+        for dest in std::iter::once(&mut self.dest).chain(&mut self.fallbacks) {
+            for previous_dest in &previous_dests {
+                if dest.keep_state_from(previous_dest) { break }
+            }
+        }
+    }
     pub fn protocol(&self) -> Protocol {
         self.src.protocol()
     }
     pub fn src_socket_addr(&self) -> Result<SocketAddr, Error> {
         self.src.to_socket_addr()
     }
-    /// TCP destinations have a socket address we can
+    /// TCP destinations have socket addresses we can
     /// talk to them on. HTTP(s) destinations do not.
-    pub fn dest_socket_addr(&self) -> Option<SocketAddr> {
-        self.dest.socket_addr()
+    pub fn dest_socket_addrs(&self) -> Option<&Upstreams<SocketAddr>> {
+        self.dest.socket_addrs()
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} to {}", self.src, self.dest)?;
+        for fallback in &self.fallbacks {
+            write!(f, " or {}", fallback)?;
+        }
+        Ok(())
     }
 }
 
@@ -131,9 +289,18 @@ mod test {
         let src: SrcLocation = src.parse().unwrap();
         Route {
             src: src.clone(),
-            dest: DestLocation::parse(dest, &src).unwrap()
+            dest: DestLocation::parse(dest, &src).unwrap(),
+            fallbacks: vec![],
+            options: RouteOptions::default()
         }
     }
+    fn route_or(src: &str, dests: &[&str]) -> Route {
+        let mut route = route(src, dests[0]);
+        for dest in &dests[1..] {
+            route.add_fallback(dest).unwrap();
+        }
+        route
+    }
 
     #[test]
     fn routes_can_be_parsed() {
@@ -231,6 +398,16 @@ mod test {
                 ],
                 2
             ),
+            // Destinations can have fallbacks:
+            (
+                vec![s("8080"), s("to"), s("./dist"), s("or"), s("=./dist/index.html"), s("and"),
+                     s("8081"), s("to"), s("./a"), s("or"), s("./b"), s("or"), s("9090")],
+                vec![
+                    route_or("http://localhost:8080/", &["./dist", "=./dist/index.html"]),
+                    route_or("http://localhost:8081/", &["./a", "./b", "http://localhost:9090"])
+                ],
+                0
+            ),
         ];
 
         for (a,b,left) in routes {
@@ -252,15 +429,35 @@ mod test {
             vec![s("9090"), s("to")],
             vec![s("9090"), s("to"), s("9091"), s("and")],
             vec![s("9090"), s("to"), s("9091"), s("and"), s("--option")],
+            vec![s("9090"), s("to"), s("9091"), s("or")],
+            vec![s("9090"), s("to"), s("9091"), s("or"), s("--option")],
+            vec![s("tcp://9090"), s("to"), s("9091"), s("or"), s("9092")],
         ];
         for r in bad_routes {
             let parsed = from_args(&r);
             assert!(
                 parsed.is_err(),
                 "Args {:?} should not successfully parse, but parsed to {:?}",
-                r, parsed.map(|(a,b)| (a, b))
+                r, parsed
             );
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn host_headers_can_be_parsed() {
+        assert_eq!("upstream".parse::<HostHeader>().unwrap(), HostHeader::Upstream);
+        assert_eq!("preserve".parse::<HostHeader>().unwrap(), HostHeader::Preserve);
+        assert_eq!("example.com".parse::<HostHeader>().unwrap(), HostHeader::Value(HeaderValue::from_static("example.com")));
+        assert!("example.com\n".parse::<HostHeader>().is_err());
+    }
+
+    #[test]
+    fn status_codes_can_be_parsed() {
+        assert_eq!(parse_status_codes("404").unwrap(), vec![StatusCode::NOT_FOUND]);
+        assert_eq!(parse_status_codes("404, 502").unwrap(), vec![StatusCode::NOT_FOUND, StatusCode::BAD_GATEWAY]);
+        assert!(parse_status_codes("").is_err());
+        assert!(parse_status_codes("404,abc").is_err());
+        assert!(parse_status_codes("1000").is_err());
+    }
+
+}
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::net::{ SocketAddr };
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use futures_util::future::join_all;
+use crate::routes::{ Route };
+use crate::location::{ Protocol };
+use crate::matcher::Matcher;
+use crate::tls::{ self, TlsFiles };
+use crate::client::{ HttpClient };
+use crate::recording::{ Recorder };
+use crate::har::{ Har };
+use crate::health;
+use crate::errors::{ Error };
+use crate::{ handle_http_requests, handle_tcp_requests };
+
+use log::{ info };
+
+/// Keep track of the servers listening on each socket address, so that
+/// the routes they serve can be swapped out while they are running.
+/// Servers are handed the receiving half of a watch channel, and will
+/// shut down gracefully once the sending half is dropped.
+pub struct Servers {
+    default_tls: Option<TlsFiles>,
+    client: HttpClient,
+    recorder: Option<Arc<Recorder>>,
+    har: Option<Arc<Har>>,
+    /// The routes currently being served.
+    routes: Vec<Route>,
+    http: HashMap<SocketAddr, (Protocol, Server<HttpState>)>,
+    tcp: HashMap<SocketAddr, Server<Route>>
+}
+
+/// A running server, which stops listening once the sender is dropped.
+struct Server<T> {
+    sender: watch::Sender<T>,
+    /// Finishes once the server has stopped listening.
+    task: JoinHandle<()>
+}
+
+/// Everything that a server needs to know to handle HTTP(S) requests.
+#[derive(Clone)]
+pub struct HttpState {
+    /// Routes to match incoming requests against
+    pub matcher: Arc<Matcher>,
+    /// Present if connections should be secured using TLS
+    pub tls: Option<TlsAcceptor>,
+    /// Used to proxy requests, and shared between servers
+    pub client: HttpClient,
+    /// Present if requests to HTTP destinations are being recorded or replayed
+    pub recorder: Option<Arc<Recorder>>,
+    /// Present if HTTP exchanges are being written to a HAR file
+    pub har: Option<Arc<Har>>
+}
+
+impl Servers {
+    /// No servers are running until routes are provided. HTTPS routes without
+    /// their own certificate and key will use the default ones if given. Every
+    /// server proxies requests using the client provided, recording them
+    /// (or replaying recordings of them) if a recorder is given, and writes
+    /// every exchange to a HAR file if one is given.
+    pub fn new(default_tls: Option<TlsFiles>, client: HttpClient, recorder: Option<Recorder>, har: Option<Har>) -> Servers {
+        Servers {
+            default_tls,
+            client,
+            recorder: recorder.map(Arc::new),
+            har: har.map(Arc::new),
+            routes: vec![],
+            http: HashMap::new(),
+            tcp: HashMap::new()
+        }
+    }
+
+    /// Update the routes that we are serving. Servers are started for any new
+    /// socket addresses, and stopped for any that are no longer needed. If an
+    /// error is returned, the servers are left untouched, unless a socket address
+    /// that moved to another protocol couldn't be listened on again (in which case
+    /// it is no longer served, and the error says so).
+    pub async fn update(&mut self, mut routes: Vec<Route>) -> Result<(), Error> {
+
+        // Remember which upstreams are unhealthy (and so on) if they haven't changed:
+        for route in &mut routes {
+            route.keep_upstream_state_from(&self.routes);
+        }
+        let served_routes = routes.clone();
+
+        // Destinations are probed once we know that the routes are being served:
+        let checked_routes: Vec<Route> = routes.iter()
+            .filter(|route| route.options.health_check.is_some())
+            .cloned()
+            .collect();
+
+        // Partition provided routes based on the SocketAddr we'll serve them on:
+        let mut http_routes: HashMap<SocketAddr, (Protocol, Vec<Route>)> = HashMap::new();
+        let mut tcp_routes: HashMap<SocketAddr, Route> = HashMap::new();
+        for route in routes {
+            let socket_addr = route.src_socket_addr()?;
+            match route.protocol() {
+                protocol @ Protocol::Http | protocol @ Protocol::Https => {
+                    let (existing, routes) = http_routes.entry(socket_addr).or_insert((protocol, vec![]));
+                    if *existing != protocol {
+                        return Err(err!("{} and {} routes cannot both be served on {}",
+                                        Protocol::Http, Protocol::Https, socket_addr))
+                    }
+                    routes.push(route);
+                },
+                Protocol::Tcp => {
+                    tcp_routes.insert(socket_addr, route);
+                },
+                Protocol::HttpStatusCode => {
+                    panic!("This is not a valid source protocol, so we shouldn't get here");
+                }
+            }
+        }
+
+        // Prepare everything that HTTP(S) servers will need, before touching the servers:
+        let mut http_states = vec![];
+        for (socket_addr, (protocol, routes)) in http_routes {
+            let tls = if protocol == Protocol::Https {
+                Some(tls::acceptor(&routes, self.default_tls.as_ref())?)
+            } else {
+                None
+            };
+            let matcher = Arc::new(Matcher::new(routes));
+            let client = self.client.clone();
+            let recorder = self.recorder.clone();
+            let har = self.har.clone();
+            http_states.push((socket_addr, protocol, HttpState { matcher, tls, client, recorder, har }));
+        }
+
+        // Listen on any socket addresses that we aren't already listening on, so that
+        // if we can't, we fail before touching the servers:
+        let wanted: Vec<(SocketAddr, Protocol)> = http_states.iter()
+            .map(|(socket_addr, protocol, _)| (*socket_addr, *protocol))
+            .chain(tcp_routes.keys().map(|socket_addr| (*socket_addr, Protocol::Tcp)))
+            .collect();
+        let mut listeners = HashMap::new();
+        for (socket_addr, _) in &wanted {
+            if !self.http.contains_key(socket_addr) && !self.tcp.contains_key(socket_addr) {
+                listeners.insert(*socket_addr, listen(*socket_addr).await?);
+            }
+        }
+
+        // Stop anything that we don't need any more. If a socket address is now being
+        // used for another protocol, we wait for the old server to let go of it:
+        let unwanted_http: Vec<SocketAddr> = self.http.iter()
+            .filter(|(socket_addr, (protocol, _))| !wanted.contains(&(**socket_addr, *protocol)))
+            .map(|(socket_addr, _)| *socket_addr)
+            .collect();
+        let unwanted_tcp: Vec<SocketAddr> = self.tcp.keys()
+            .filter(|socket_addr| !wanted.contains(&(**socket_addr, Protocol::Tcp)))
+            .cloned()
+            .collect();
+        let mut stopped = vec![];
+        for socket_addr in unwanted_http {
+            let (_, Server { task, .. }) = self.http.remove(&socket_addr).unwrap();
+            stopped.push((socket_addr, task));
+        }
+        for socket_addr in unwanted_tcp {
+            let Server { task, .. } = self.tcp.remove(&socket_addr).unwrap();
+            stopped.push((socket_addr, task));
+        }
+        let mut error = None;
+        for (socket_addr, task) in stopped {
+            if !wanted.iter().any(|(a, _)| *a == socket_addr) { continue }
+            let _ = task.await;
+            match listen(socket_addr).await {
+                Ok(listener) => { listeners.insert(socket_addr, listener); },
+                Err(e) => { error.get_or_insert(err!("{} (so it is no longer being served)", e)); }
+            }
+        }
+
+        // Hand updated routes to existing servers (which only stop once we drop
+        // the sender, so are still listening), or start new ones:
+        for (socket_addr, protocol, state) in http_states {
+            if let Some((_, server)) = self.http.get(&socket_addr) {
+                let _ = server.sender.broadcast(state);
+            } else if let Some(listener) = listeners.remove(&socket_addr) {
+                let (sender, receiver) = watch::channel(state);
+                let task = tokio::spawn(handle_http_requests(listener, socket_addr, receiver));
+                self.http.insert(socket_addr, (protocol, Server { sender, task }));
+            }
+        }
+        for (socket_addr, route) in tcp_routes {
+            if let Some(server) = self.tcp.get(&socket_addr) {
+                let _ = server.sender.broadcast(route);
+            } else if let Some(listener) = listeners.remove(&socket_addr) {
+                let (sender, receiver) = watch::channel(route);
+                let task = tokio::spawn(handle_tcp_requests(listener, socket_addr, receiver));
+                self.tcp.insert(socket_addr, Server { sender, task });
+            }
+        }
+
+        health::spawn_checks(&checked_routes, &self.client);
+        self.routes = served_routes;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(())
+        }
+    }
+
+    /// Wait until every server that we've started has stopped (this
+    /// shouldn't happen unless they all fail).
+    pub async fn closed(&mut self) {
+        let http = self.http.values_mut().map(|(_, server)| server.sender.closed());
+        let tcp = self.tcp.values_mut().map(|server| server.sender.closed());
+        futures_util::join!(join_all(http), join_all(tcp));
+    }
+}
+
+async fn listen(socket_addr: SocketAddr) -> Result<TcpListener, Error> {
+    TcpListener::bind(socket_addr).await.map_err(|e| err!("error listening on {}: {}", socket_addr, e))
+}
+
+/// Wait until the sending half of a watch channel has been dropped, which
+/// is our signal for a server to stop listening.
+pub async fn shutdown<T: Clone>(mut receiver: watch::Receiver<T>, socket_addr: SocketAddr) {
+    while receiver.recv().await.is_some() {}
+    info!("No longer listening on {}", socket_addr);
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::client::{ self, ClientOptions };
+    use crate::routes::{ parse_route };
+    use crate::location::{ Balance };
+
+    fn servers() -> Servers {
+        Servers::new(None, client::new(&ClientOptions::default()), None, None)
+    }
+
+    fn route(src: &str, port: u16) -> Route {
+        parse_route(&format!("{}127.0.0.1:{}", src, port), "9999").unwrap()
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Find a port that nothing is listening on.
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn ports_that_cannot_be_listened_on_are_reported() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let free_port = free_port();
+        let mut servers = servers();
+        servers.update(vec![route("", free_port)]).await.unwrap();
+
+        // The existing servers are left alone if a new port can't be listened on:
+        let err = servers.update(vec![route("", taken_port)]).await.unwrap_err();
+        assert!(err.to_string().contains(&format!("error listening on {}", addr(taken_port))));
+        assert!(servers.http.contains_key(&addr(free_port)));
+        assert!(!servers.http.contains_key(&addr(taken_port)));
+
+        // And we try again next time:
+        drop(taken);
+        servers.update(vec![route("", taken_port)]).await.unwrap();
+        assert!(servers.http.contains_key(&addr(taken_port)));
+        assert!(!servers.http.contains_key(&addr(free_port)));
+    }
+
+    #[tokio::test]
+    async fn ports_can_move_between_protocols() {
+        let port = free_port();
+        let mut servers = servers();
+        servers.update(vec![route("", port)]).await.unwrap();
+        servers.update(vec![route("tcp://", port)]).await.unwrap();
+        assert!(servers.http.is_empty());
+        assert!(servers.tcp.contains_key(&addr(port)));
+
+        servers.update(vec![route("", port)]).await.unwrap();
+        assert!(servers.tcp.is_empty());
+        assert!(servers.http.contains_key(&addr(port)));
+    }
+
+    #[tokio::test]
+    async fn unhealthy_upstreams_are_remembered_across_reloads() {
+        let port = free_port();
+        let routes = || vec![parse_route(&format!("127.0.0.1:{}", port), "9991,9992").unwrap()];
+        let mut servers = servers();
+        servers.update(routes()).await.unwrap();
+        let upstreams = servers.routes[0].dest.url_upstreams().unwrap();
+        let (_, guard) = upstreams.pick(Balance::RoundRobin, &[]).unwrap();
+        guard.failed(1, "oops");
+
+        // Reloading the same routes keeps the first upstream out of rotation:
+        servers.update(routes()).await.unwrap();
+        let upstreams = servers.routes[0].dest.url_upstreams().unwrap();
+        for _ in 0..3 {
+            assert_eq!(upstreams.pick(Balance::RoundRobin, &[]).unwrap().0, "http://localhost:9992");
+        }
+
+        // But routes to other upstreams start afresh:
+        servers.update(vec![parse_route(&format!("127.0.0.1:{}", port), "9991,9993").unwrap()]).await.unwrap();
+        let upstreams = servers.routes[0].dest.url_upstreams().unwrap();
+        assert_eq!(upstreams.pick(Balance::RoundRobin, &[]).unwrap().0, "http://localhost:9991");
+    }
+
+}
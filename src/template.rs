@@ -0,0 +1,190 @@
+use std::path::Path;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use hyper::{ Body, Response, HeaderMap, header, http::request };
+use percent_encoding::percent_decode_str;
+use serde_json::Value;
+use tokio::fs;
+use uuid::Uuid;
+use crate::files::{ self, Meta };
+use crate::util::{ rfc3339 };
+
+/// The details of a request that a template can refer to.
+pub struct RequestData<'a> {
+    method: &'a str,
+    uri: String,
+    captures: &'a [(String, String)],
+    query: &'a str,
+    headers: &'a HeaderMap,
+    body: String,
+    /// The body parsed as JSON, if it can be.
+    json: Option<Value>
+}
+
+impl <'a> RequestData<'a> {
+    pub fn new(req: &'a request::Parts, captures: &'a [(String, String)], body: &[u8]) -> RequestData<'a> {
+        RequestData {
+            method: req.method.as_str(),
+            uri: req.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/").to_owned(),
+            captures,
+            query: req.uri.query().unwrap_or(""),
+            headers: &req.headers,
+            body: String::from_utf8_lossy(body).into_owned(),
+            json: serde_json::from_slice(body).ok()
+        }
+    }
+
+    /// Look up the value of an expression (eg 'query.page' or 'body.user.name').
+    /// None is returned if the expression isn't one we know about, and an
+    /// empty string if it is but there's nothing there.
+    fn lookup(&self, expr: &str) -> Option<Value> {
+        let (namespace, name) = expr.split_once('.').unwrap_or((expr, ""));
+        let value = match (namespace, name) {
+            ("method", "") => self.method.into(),
+            ("uri", "") => self.uri.clone().into(),
+            ("now", "") => rfc3339(now_secs()).into(),
+            ("timestamp", "") => now_secs().into(),
+            ("uuid", "") => Uuid::new_v4().to_string().into(),
+            ("body", "") => self.body.clone().into(),
+            ("path", name) if !name.is_empty() => {
+                self.captures.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone()).unwrap_or_default().into()
+            },
+            ("query", name) if !name.is_empty() => {
+                query_param(self.query, name).unwrap_or_default().into()
+            },
+            ("header", name) if !name.is_empty() => {
+                self.headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or_default().into()
+            },
+            ("body", fields) => {
+                let mut value = self.json.as_ref();
+                for field in fields.split('.') {
+                    value = value.and_then(|v| match v {
+                        Value::Array(items) => field.parse::<usize>().ok().and_then(|idx| items.get(idx)),
+                        v => v.get(field)
+                    });
+                }
+                value.cloned().unwrap_or_else(|| "".into())
+            },
+            _ => return None
+        };
+        Some(value)
+    }
+}
+
+/// Respond with the template at the path given, rendered using details of the request.
+/// If the template is JSON, strings are escaped so that they can be placed in quotes.
+/// As with files, templates can have a '.meta' file alongside them.
+pub async fn serve(path: &Path, req: &RequestData<'_>) -> Response<Body> {
+    let template = match fs::read_to_string(path).await {
+        Ok(template) => template,
+        Err(e) => return files::not_found(path, e)
+    };
+    let meta = match Meta::load(path).await {
+        Ok(meta) => meta,
+        Err(e) => return files::invalid_meta(e)
+    };
+    let mime = mime_guess::from_path(path).first_or_text_plain();
+    let is_json = mime == mime_guess::mime::APPLICATION_JSON;
+
+    let res = Response::builder()
+        .header(header::CONTENT_TYPE, mime.as_ref())
+        .body(Body::from(render(&template, req, is_json)))
+        .unwrap();
+    match meta {
+        Some(meta) => meta.apply(res).await,
+        None => res
+    }
+}
+
+/// Replace each '{{ expression }}' in a template with its value. Expressions
+/// that we don't recognise are left as they are.
+pub fn render(template: &str, req: &RequestData, is_json: bool) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start+2..].find("}}") {
+            Some(end) => start + 2 + end,
+            None => break
+        };
+        out.push_str(&rest[..start]);
+        match req.lookup(rest[start+2..end].trim()) {
+            Some(Value::String(s)) if is_json => {
+                let quoted = Value::String(s).to_string();
+                out.push_str(&quoted[1..quoted.len()-1]);
+            },
+            Some(Value::String(s)) => out.push_str(&s),
+            Some(value) => out.push_str(&value.to_string()),
+            None => out.push_str(&rest[start..end+2])
+        }
+        rest = &rest[end+2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Find the first value of a query param, decoding it.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if decode(key) == name { Some(decode(value)) } else { None }
+    })
+}
+
+fn decode(s: &str) -> String {
+    percent_decode_str(&s.replace('+', " ")).decode_utf8_lossy().into_owned()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use hyper::Request;
+
+    fn parts(uri: &str) -> request::Parts {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("x-token", "abc \"123\"")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    #[test]
+    fn templates_can_use_request_data() {
+        let req = parts("/users/1?page=2&name=Bob+Smith");
+        let captures = vec![("id".to_owned(), "1".to_owned())];
+        let body = br#"{"user": {"name": "Alice", "tags": ["a", "b"], "age": 30}}"#;
+        let data = RequestData::new(&req, &captures, body);
+
+        let template = "{{method}} {{ uri }}: id={{ path.id }} page={{ query.page }} name={{ query.name }} \
+                        token={{ header.x-token }} user={{ body.user.name }} tag={{ body.user.tags.1 }} \
+                        age={{ body.user.age }} missing={{ query.missing }}|{{ body.nope }} unknown={{ wat }}";
+        assert_eq!(render(template, &data, false),
+                   "POST /users/1?page=2&name=Bob+Smith: id=1 page=2 name=Bob Smith token=abc \"123\" user=Alice \
+                    tag=b age=30 missing=| unknown={{ wat }}");
+
+        // Strings are escaped in JSON templates:
+        assert_eq!(render(r#"{"token": "{{ header.x-token }}", "user": {{ body.user }}}"#, &data, true),
+                   r#"{"token": "abc \"123\"", "user": {"age":30,"name":"Alice","tags":["a","b"]}}"#);
+
+        // Unclosed expressions are left alone:
+        assert_eq!(render("{{ method }} {{ method", &data, false), "POST {{ method");
+    }
+
+    #[test]
+    fn uuids_are_random() {
+        let req = parts("/");
+        let data = RequestData::new(&req, &[], b"");
+        let uuid = render("{{ uuid }}", &data, false);
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        assert!(["8", "9", "a", "b"].contains(&&uuid[19..20]));
+        assert_ne!(uuid, render("{{ uuid }}", &data, false));
+    }
+
+}
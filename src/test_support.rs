@@ -0,0 +1,15 @@
+//! Helpers shared by the tests in different modules.
+
+use hyper::{ Body, Response };
+use tempfile::TempDir;
+
+/// Create a fresh directory to put test files in. It's removed once dropped.
+pub fn temp_dir() -> TempDir {
+    tempfile::Builder::new().prefix("weave-test-").tempdir().unwrap()
+}
+
+/// Read the whole body of a response into a string.
+pub async fn body_string(res: Response<Body>) -> String {
+    let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{ self, BufReader };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::path::{ Path, PathBuf };
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ Context, Poll };
+use tokio::io::{ AsyncRead, AsyncWrite };
+use tokio::net::TcpStream;
+use tokio_rustls::{ TlsAcceptor, server::TlsStream };
+use tokio_rustls::rustls::{ ServerConfig, NoClientAuth, ResolvesServerCert, ClientHello, sign };
+use tokio_rustls::rustls::internal::pemfile;
+use url::Host;
+use crate::errors::{ Error };
+use crate::routes::{ Route };
+
+use log::{ info, warn };
+
+/// The certificate chain and private key (both PEM encoded)
+/// to use when serving HTTPS routes.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf
+}
+
+/// An incoming connection, which may or may not be secured using TLS.
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>)
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_read(cx, buf)
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_write(cx, buf)
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_flush(cx)
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_shutdown(cx)
+        }
+    }
+}
+
+/// Build something that can accept TLS connections for the HTTPS routes being served
+/// on a single socket address. Routes without their own certificate use the default
+/// one if provided, and fall back to a self-signed certificate. Where routes match on
+/// different hosts, the certificate to use is picked using SNI.
+pub fn acceptor(routes: &[Route], default_files: Option<&TlsFiles>) -> Result<TlsAcceptor, Error> {
+
+    let mut resolver = CertResolver {
+        exact: HashMap::new(),
+        wildcard: vec![],
+        default: None
+    };
+
+    for route in routes {
+        let domain = match route.src.host() {
+            Host::Domain(domain) => Some(domain.as_str()),
+            _ => None
+        };
+
+        let key = match route.options.tls.as_ref().or(default_files) {
+            Some(files) => load_certified_key(files)?,
+            None => self_signed_certified_key(domain.unwrap_or("localhost"))?
+        };
+
+        // Routes that don't match on a specific host are served the default certificate,
+        // and the first certificate we come across is used if there's no better option:
+        match domain {
+            Some(domain) if domain != "localhost" => {
+                if let Some(suffix) = domain.strip_prefix('*') {
+                    resolver.wildcard.push((suffix.to_owned(), key.clone()));
+                } else {
+                    resolver.exact.entry(domain.to_owned()).or_insert_with(|| key.clone());
+                }
+                resolver.default.get_or_insert(key);
+            },
+            _ => {
+                resolver.default = Some(key);
+            }
+        }
+    }
+
+    // Prefer longer (more specific) wildcards:
+    resolver.wildcard.sort_by(|a,b| a.0.len().cmp(&b.0.len()).reverse());
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = Arc::new(resolver);
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Pick a certificate based on the server name that the client asks for.
+struct CertResolver {
+    exact: HashMap<String, sign::CertifiedKey>,
+    wildcard: Vec<(String, sign::CertifiedKey)>,
+    default: Option<sign::CertifiedKey>
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+        let name = client_hello.server_name().map(|name| {
+            let name: &str = name.into();
+            name.to_ascii_lowercase()
+        });
+
+        if let Some(name) = name {
+            if let Some(key) = self.exact.get(&name) {
+                return Some(key.clone())
+            }
+            let wildcard = self.wildcard.iter().find(|(suffix, _)| {
+                name.len() > suffix.len() && name.ends_with(&**suffix)
+            });
+            if let Some((_, key)) = wildcard {
+                return Some(key.clone())
+            }
+        }
+
+        self.default.clone()
+    }
+}
+
+/// Load a certificate chain and private key from the files provided.
+fn load_certified_key(files: &TlsFiles) -> Result<sign::CertifiedKey, Error> {
+    let cert_bytes = fs::read(&files.cert).map_err(|e| {
+        err!("could not read certificate '{}': {}", files.cert.display(), e)
+    })?;
+    let key_bytes = fs::read(&files.key).map_err(|e| {
+        err!("could not read private key '{}': {}", files.key.display(), e)
+    })?;
+    parse_certified_key(&cert_bytes, &key_bytes).map_err(|e| {
+        err!("could not use certificate '{}' and key '{}': {}", files.cert.display(), files.key.display(), e)
+    })
+}
+
+/// Parse PEM encoded certificates and a PKCS8 or RSA private key.
+fn parse_certified_key(cert_bytes: &[u8], key_bytes: &[u8]) -> Result<sign::CertifiedKey, Error> {
+    let certs = pemfile::certs(&mut BufReader::new(cert_bytes))
+        .map_err(|_| err!("certificates are not valid PEM"))?;
+    if certs.is_empty() {
+        return Err(err!("no certificates were found"))
+    }
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(key_bytes))
+        .map_err(|_| err!("private key is not valid PEM"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(key_bytes))
+            .map_err(|_| err!("private key is not valid PEM"))?;
+    }
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => return Err(err!("no PKCS8 or RSA private key was found"))
+    };
+
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| err!("the private key is not a supported type"))?;
+    Ok(sign::CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// Hand back a self-signed certificate for the domain provided. Certificates are
+/// cached so that browser exceptions for them continue to work across restarts.
+fn self_signed_certified_key(domain: &str) -> Result<sign::CertifiedKey, Error> {
+
+    // Use the cached certificate if there is one:
+    let cached_path = cache_dir().map(|dir| dir.join(format!("{}.pem", domain.replace('*', "_"))));
+    if let Some(path) = &cached_path {
+        if let Ok(pem) = fs::read(path) {
+            match parse_certified_key(&pem, &pem) {
+                Ok(key) => return Ok(key),
+                Err(e) => warn!("Ignoring cached certificate '{}': {}", path.display(), e)
+            }
+        }
+    }
+
+    // Generate a new certificate, also valid for loopback addresses if it's for localhost:
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_owned()]);
+    if domain == "localhost" {
+        params.subject_alt_names.push(rcgen::SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        params.subject_alt_names.push(rcgen::SanType::IpAddress(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+    let cert = rcgen::Certificate::from_params(params)
+        .map_err(|e| err!("could not generate a self-signed certificate for '{}': {}", domain, e))?;
+    let cert_pem = cert.serialize_pem()
+        .map_err(|e| err!("could not generate a self-signed certificate for '{}': {}", domain, e))?;
+    let pem = format!("{}{}", cert_pem, cert.serialize_private_key_pem());
+
+    match &cached_path {
+        Some(path) => {
+            match write_private_file(path, pem.as_bytes()) {
+                Ok(()) => info!("Generated a self-signed certificate for {} at {}", domain, path.display()),
+                Err(e) => warn!("Generated a self-signed certificate for {}, but could not save it: {}", domain, e)
+            }
+        },
+        None => info!("Generated a self-signed certificate for {}", domain)
+    }
+
+    parse_certified_key(pem.as_bytes(), pem.as_bytes())
+}
+
+/// Write a file that only the current user can read, creating parent directories as needed.
+fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    opts.open(path)?.write_all(contents)
+}
+
+/// Where do we cache things like generated certificates?
+fn cache_dir() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+    Some(dir.join("weave"))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn self_signed_certificates_can_be_parsed() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let pem = format!("{}{}", cert.serialize_pem().unwrap(), cert.serialize_private_key_pem());
+        assert!(parse_certified_key(pem.as_bytes(), pem.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn invalid_certificates_are_rejected() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let key_pem = cert.serialize_private_key_pem();
+        // No key:
+        assert!(parse_certified_key(cert_pem.as_bytes(), cert_pem.as_bytes()).is_err());
+        // No certificate:
+        assert!(parse_certified_key(key_pem.as_bytes(), key_pem.as_bytes()).is_err());
+    }
+
+}